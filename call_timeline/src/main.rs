@@ -0,0 +1,84 @@
+use lambda_http::{
+    http::{
+        header::{HeaderValue, CONTENT_TYPE},
+        StatusCode,
+    },
+    lambda,
+    request::RequestContext,
+    Body, IntoResponse, Request, RequestExt, Response,
+};
+use lambda_runtime::{error::HandlerError, Context};
+use log::Level::Info;
+use models::{
+    call::{Call, ReadError},
+    timeline::TimelineEvent,
+};
+use rusoto_core::Region;
+use serde_json::json;
+use simple_logger::init_with_level;
+use std::env;
+use uuid::Uuid;
+
+fn main() {
+    init_with_level(Info).unwrap();
+    lambda!(handler);
+}
+
+/// GET /calls/{id}/timeline: who called, and everything recorded about the
+/// call since, oldest first. API Gateway only lets requests through with a
+/// token from the user pool.
+#[tokio::main]
+async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
+    let authorized = match request.request_context() {
+        RequestContext::ApiGateway { authorizer, .. } => authorizer.contains_key("claims"),
+        RequestContext::Alb { .. } => false,
+    };
+    // Checked again in case the function is ever exposed without the authorizer
+    if !authorized {
+        let mut response = "Unauthorized".into_response();
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return Ok(response);
+    }
+    let call_id = match request
+        .path_parameters()
+        .get("id")
+        .and_then(|id| Uuid::parse_str(id).ok())
+    {
+        Some(call_id) => call_id,
+        None => {
+            let mut response = "Invalid call id".into_response();
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(response);
+        }
+    };
+    let timeline_table = env::var("TIMELINE_TABLE")?;
+    let events = TimelineEvent::get_timeline(timeline_table, Region::UsEast1, call_id)
+        .await
+        .ok_or_else(|| HandlerError::from("TimelineReadFail"))?;
+    // The call is only missing if it was deleted; its events are still useful
+    let call = match Call::get_call(
+        env::var("CALL_TABLE")?,
+        Region::UsEast1,
+        call_id.to_string(),
+    )
+    .await
+    {
+        Ok(call) => Some(call),
+        Err(ReadError::NotFound) => None,
+        Err(_e) => return Err(HandlerError::from("CallReadFail")),
+    };
+    let history = json!({
+        "call_id": call_id,
+        "from": call.as_ref().map(|call| &call.phone_number),
+        "caller": call.as_ref().map(|call| &call.caller),
+        "events": events,
+    });
+    let mut response = serde_json::to_string(&history)
+        .map_err(|_e| HandlerError::from("TimelineSerializeFail"))?
+        .into_response();
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str("application/json").unwrap(),
+    );
+    Ok(response)
+}
@@ -0,0 +1,619 @@
+use dynomite::dynamodb::{DynamoDbClient, PutItemError};
+use lambda_http::{
+    http::{
+        header::{HeaderValue, CONTENT_TYPE},
+        StatusCode,
+    },
+    lambda,
+    request::RequestContext,
+    Body, IntoResponse, Request, RequestExt, Response,
+};
+use lambda_runtime::{error::HandlerError, Context};
+use log::{warn, Level::Info};
+use models::{
+    group::{Group, GroupWriteError},
+    users::{ContactMethod, NotificationRule, User},
+    validate::ValidationError,
+};
+use rusoto_core::{Region, RusotoError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use simple_logger::init_with_level;
+use std::env;
+use uuid::Uuid;
+
+mod slots;
+
+fn main() {
+    init_with_level(Info).unwrap();
+    lambda!(handler);
+}
+
+/// Body of POST /groups and PUT /groups/{id}. Members are managed through
+/// the group's users.
+#[derive(Deserialize, Debug)]
+struct GroupInput {
+    /// Only needed when creating a group
+    group_id: Option<String>,
+    name: String,
+    utc_offset: i32,
+    numbers: Vec<String>,
+    #[serde(default)]
+    greeting: Option<String>,
+}
+
+/// Body of POST /groups/{id}/users and PUT /groups/{id}/users/{user_id}
+#[derive(Deserialize, Debug)]
+struct UserInput {
+    name: String,
+    number: String,
+    #[serde(default)]
+    contacts: Vec<ContactMethod>,
+    #[serde(default)]
+    rules: Vec<NotificationRule>,
+}
+
+impl UserInput {
+    fn into_user(self, uuid: String, group_id: String) -> User {
+        let mut user = User::new_user(uuid, group_id, self.name, self.number);
+        for contact in self.contacts {
+            user.add_contact(contact.kind, contact.address, contact.label);
+        }
+        user.set_rules(self.rules);
+        user
+    }
+}
+
+struct Tables {
+    groups: String,
+    numbers: String,
+    slots: String,
+    /// Generated schedules, which calls are routed by
+    schedules: String,
+    client: DynamoDbClient,
+}
+
+fn json_response<T: Serialize>(
+    status: StatusCode,
+    body: &T,
+) -> Result<Response<Body>, HandlerError> {
+    let mut response = serde_json::to_string(body)
+        .map_err(|_e| HandlerError::from("SerializeFail"))?
+        .into_response();
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str("application/json").unwrap(),
+    );
+    Ok(response)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Result<Response<Body>, HandlerError> {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn invalid(e: ValidationError) -> Result<Response<Body>, HandlerError> {
+    json_response(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        &json!({ "error": "Invalid input", "field": e.field, "reason": e.reason }),
+    )
+}
+
+fn parse_body<T: DeserializeOwned>(request: &Request) -> Result<T, String> {
+    let text = match request.body() {
+        Body::Text(text) => text.as_str(),
+        Body::Binary(bytes) => std::str::from_utf8(bytes).map_err(|e| e.to_string())?,
+        Body::Empty => "",
+    };
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
+
+/// Refuses numbers that already ring through to another group
+async fn claimed_number(tables: &Tables, group: &Group) -> Option<String> {
+    for number in &group.numbers {
+        let owner = Group::get_group_by_number(
+            tables.groups.clone(),
+            tables.numbers.clone(),
+            &tables.client,
+            number.clone(),
+        )
+        .await;
+        if let Some(owner) = owner {
+            if owner.group_id != group.group_id {
+                return Some(number.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Validates and stores a new or changed group, freeing the numbers in
+/// `given_up` it no longer has
+async fn save_group(
+    tables: &Tables,
+    mut group: Group,
+    created: bool,
+    given_up: &[String],
+) -> Result<Response<Body>, HandlerError> {
+    if let Err(e) = group.validate() {
+        return invalid(e);
+    }
+    if let Some(number) = claimed_number(tables, &group).await {
+        return error_response(
+            StatusCode::CONFLICT,
+            &format!("{} belongs to another group", number),
+        );
+    }
+    let written = if created {
+        group
+            .create_group(
+                tables.groups.clone(),
+                tables.numbers.clone(),
+                &tables.client,
+            )
+            .await
+    } else {
+        group
+            .write_group(
+                tables.groups.clone(),
+                tables.numbers.clone(),
+                &tables.client,
+                given_up,
+            )
+            .await
+    };
+    match written {
+        Ok(()) if created => json_response(StatusCode::CREATED, &group),
+        Ok(()) => json_response(StatusCode::OK, &group),
+        Err(e) => write_failed(&group, created, e),
+    }
+}
+
+/// Answers a failed group write, with a 409 for conflicts the caller can
+/// resolve by trying again
+fn write_failed(
+    group: &Group,
+    created: bool,
+    e: GroupWriteError,
+) -> Result<Response<Body>, HandlerError> {
+    match e {
+        GroupWriteError::Group(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => {
+            error_response(
+                StatusCode::CONFLICT,
+                if created {
+                    "A group with this id already exists"
+                } else {
+                    "The group was changed by someone else"
+                },
+            )
+        }
+        GroupWriteError::NumberClaimed(number) => error_response(
+            StatusCode::CONFLICT,
+            &format!("{} belongs to another group", number),
+        ),
+        e => {
+            warn!("Writing group {} failed: {:?}", group.group_id, e);
+            Err(HandlerError::from("GroupWriteFail"))
+        }
+    }
+}
+
+/// /groups and /groups/{id}
+async fn groups(
+    request: &Request,
+    tables: &Tables,
+    group_id: Option<&str>,
+) -> Result<Response<Body>, HandlerError> {
+    let group = match group_id {
+        Some(group_id) => {
+            match Group::get_group(tables.groups.clone(), &tables.client, group_id.to_string())
+                .await
+            {
+                Some(group) => Some(group),
+                None => return error_response(StatusCode::NOT_FOUND, "No such group"),
+            }
+        }
+        None => None,
+    };
+    match (request.method().as_str(), group) {
+        ("GET", None) => {
+            let groups = Group::get_groups(tables.groups.clone(), &tables.client)
+                .await
+                .map_err(|_e| HandlerError::from("GroupReadFail"))?;
+            json_response(StatusCode::OK, &groups)
+        }
+        ("POST", None) => {
+            let input: GroupInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            let mut group = Group::new_group(
+                input.group_id.unwrap_or_default(),
+                input.name,
+                input.utc_offset,
+                input.numbers,
+            );
+            group.greeting = input.greeting;
+            save_group(tables, group, true, &[]).await
+        }
+        ("GET", Some(group)) => json_response(StatusCode::OK, &group),
+        ("PUT", Some(mut group)) => {
+            let input: GroupInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            if input.group_id.as_deref().unwrap_or(&group.group_id) != group.group_id {
+                return error_response(StatusCode::BAD_REQUEST, "A group's id can't be changed");
+            }
+            let given_up = std::mem::replace(&mut group.numbers, input.numbers);
+            group.name = input.name;
+            group.utc_offset = input.utc_offset;
+            group.greeting = input.greeting;
+            save_group(tables, group, false, &given_up).await
+        }
+        ("DELETE", Some(group)) => {
+            group
+                .delete_group(
+                    tables.groups.clone(),
+                    tables.numbers.clone(),
+                    &tables.client,
+                )
+                .await
+                .map_err(|_e| HandlerError::from("GroupDeleteFail"))?;
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::Empty)
+                .unwrap())
+        }
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/// /groups/{id}/users and /groups/{id}/users/{user_id}. Users are stored as
+/// members of their group.
+async fn users(
+    request: &Request,
+    tables: &Tables,
+    group_id: &str,
+    user_id: Option<&str>,
+) -> Result<Response<Body>, HandlerError> {
+    let mut group =
+        match Group::get_group(tables.groups.clone(), &tables.client, group_id.to_string()).await {
+            Some(group) => group,
+            None => return error_response(StatusCode::NOT_FOUND, "No such group"),
+        };
+    let index = match user_id {
+        Some(user_id) => match group.members.iter().position(|user| user.uuid() == user_id) {
+            Some(index) => Some(index),
+            None => return error_response(StatusCode::NOT_FOUND, "No such user"),
+        },
+        None => None,
+    };
+    match (request.method().as_str(), index) {
+        ("GET", None) => json_response(StatusCode::OK, &group.members),
+        ("POST", None) => {
+            let input: UserInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            let user = input.into_user(Uuid::new_v4().to_string(), group.group_id.clone());
+            if let Err(e) = user.validate() {
+                return invalid(e);
+            }
+            group.members.push(user.clone());
+            save_members(tables, group, StatusCode::CREATED, &user).await
+        }
+        ("GET", Some(index)) => json_response(StatusCode::OK, &group.members[index]),
+        ("PUT", Some(index)) => {
+            let input: UserInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            let user = input.into_user(
+                group.members[index].uuid().to_string(),
+                group.group_id.clone(),
+            );
+            if let Err(e) = user.validate() {
+                return invalid(e);
+            }
+            group.members[index] = user.clone();
+            save_members(tables, group, StatusCode::OK, &user).await
+        }
+        ("DELETE", Some(index)) => {
+            group.members.remove(index);
+            save_members(tables, group, StatusCode::NO_CONTENT, &()).await
+        }
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/// Stores the group after a change to its members, answering with `body`
+async fn save_members<T: Serialize>(
+    tables: &Tables,
+    mut group: Group,
+    status: StatusCode,
+    body: &T,
+) -> Result<Response<Body>, HandlerError> {
+    let written = group
+        .write_group(
+            tables.groups.clone(),
+            tables.numbers.clone(),
+            &tables.client,
+            &[],
+        )
+        .await;
+    if let Err(e) = written {
+        return write_failed(&group, false, e);
+    }
+    match status {
+        StatusCode::NO_CONTENT => Ok(Response::builder()
+            .status(status)
+            .body(Body::Empty)
+            .unwrap()),
+        _ => json_response(status, body),
+    }
+}
+
+/// CRUD for groups, their users and schedule slots, for admin tooling. API
+/// Gateway only lets requests through with a token from the user pool.
+#[tokio::main]
+async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
+    let tables = Tables {
+        groups: env::var("GROUPS_TABLE")?,
+        numbers: env::var("NUMBER_TABLE")?,
+        slots: env::var("SLOT_TABLE")?,
+        schedules: env::var("GROUP_TABLE")?,
+        client: DynamoDbClient::new(Region::UsEast1),
+    };
+    route(&request, &tables).await
+}
+
+async fn route(request: &Request, tables: &Tables) -> Result<Response<Body>, HandlerError> {
+    let (resource, authorized) = match request.request_context() {
+        RequestContext::ApiGateway {
+            resource_path,
+            authorizer,
+            ..
+        } => (resource_path, authorizer.contains_key("claims")),
+        RequestContext::Alb { .. } => (String::new(), false),
+    };
+    // Checked again in case the function is ever exposed without the authorizer
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+    let params = request.path_parameters();
+    let child_id = params.get("user_id").or_else(|| params.get("slot_id"));
+    match (resource.as_str(), params.get("id"), child_id) {
+        ("/groups", None, None) => groups(request, tables, None).await,
+        ("/groups/{id}", Some(group_id), None) => groups(request, tables, Some(group_id)).await,
+        ("/groups/{id}/users", Some(group_id), None) => {
+            users(request, tables, group_id, None).await
+        }
+        ("/groups/{id}/users/{user_id}", Some(group_id), Some(user_id)) => {
+            users(request, tables, group_id, Some(user_id)).await
+        }
+        ("/groups/{id}/slots", Some(group_id), None) => {
+            slots::slots(request, tables, group_id, None).await
+        }
+        ("/groups/{id}/slots/{slot_id}", Some(group_id), Some(slot_id)) => {
+            slots::slots(request, tables, group_id, Some(slot_id)).await
+        }
+        ("/groups/{id}/schedule", Some(group_id), None) => {
+            slots::schedule(request, tables, group_id).await
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_http::request::from_str;
+    use rusoto_core::{credential::StaticProvider, HttpClient};
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tokio::runtime::Builder;
+
+    const CONDITION_FAILED: &str = r#"{"__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException", "message": "The conditional request failed"}"#;
+
+    /// Answers DynamoDB requests with whatever `answer` gives for their
+    /// operation and table, and keeps a log of them
+    fn dynamodb_stand_in<F>(answer: F) -> (Tables, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str, &str) -> (u16, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let requests = log.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut operation = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let mut header = line.splitn(2, ':');
+                    let name = header.next().unwrap().to_ascii_lowercase();
+                    let value = header.next().unwrap_or_default().trim();
+                    if name == "content-length" {
+                        length = value.parse().unwrap();
+                    } else if name == "x-amz-target" {
+                        operation = value.rsplit('.').next().unwrap().to_owned();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                let table = body["TableName"].as_str().unwrap_or_default();
+                requests
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", operation, table));
+                let (status, reply) = answer(&operation, table);
+                write!(
+                    writer,
+                    "HTTP/1.1 {} Stand-in\r\ncontent-type: application/x-amz-json-1.0\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    reply.len(),
+                    reply
+                )
+                .unwrap();
+            }
+        });
+        let tables = Tables {
+            groups: "groups".to_owned(),
+            numbers: "numbers".to_owned(),
+            slots: "slots".to_owned(),
+            schedules: "schedules".to_owned(),
+            client: DynamoDbClient::new_with(
+                HttpClient::new().unwrap(),
+                StaticProvider::new_minimal("AKIDEXAMPLE".to_owned(), "secret".to_owned()),
+                Region::Custom {
+                    name: "us-east-1".to_owned(),
+                    endpoint: format!("http://127.0.0.1:{}", port),
+                },
+            ),
+        };
+        (tables, log)
+    }
+
+    fn request(
+        method: &str,
+        resource: &str,
+        id: Option<&str>,
+        body: &str,
+        claims: bool,
+    ) -> Request {
+        let authorizer = if claims {
+            serde_json::json!({ "claims": { "sub": "admin" } })
+        } else {
+            serde_json::json!({})
+        };
+        let event = serde_json::json!({
+            "path": resource.replace("{id}", id.unwrap_or_default()),
+            "httpMethod": method,
+            "headers": { "Host": "wt6mne2s9k.execute-api.us-east-1.amazonaws.com" },
+            "pathParameters": id.map(|id| serde_json::json!({ "id": id })),
+            "queryStringParameters": null,
+            "body": body,
+            "requestContext": {
+                "accountId": "123456789012",
+                "resourceId": "us4z18",
+                "stage": "test",
+                "requestId": "41b45ea3-70b5-11e6-b7bd-69b5aaebc7d9",
+                "resourcePath": resource,
+                "httpMethod": method,
+                "authorizer": authorizer,
+                "apiId": "wt6mne2s9k",
+                "identity": { "sourceIp": "192.168.100.1" },
+            },
+        });
+        from_str(&event.to_string()).unwrap()
+    }
+
+    fn respond(tables: &Tables, request: Request) -> (StatusCode, Value) {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let response = runtime.block_on(route(&request, tables)).unwrap();
+        let body = match response.body() {
+            Body::Text(text) => serde_json::from_str(text).unwrap(),
+            _ => Value::Null,
+        };
+        (response.status(), body)
+    }
+
+    const NEW_GROUP: &str = r#"{"group_id": "acme-support", "name": "Acme Support", "utc_offset": -14400, "numbers": ["+12183957949"]}"#;
+
+    #[test]
+    fn test_unauthorized_and_not_found() {
+        let (tables, log) = dynamodb_stand_in(|_, _| (200, "{}".to_owned()));
+        let (status, _) = respond(&tables, request("GET", "/groups", None, "", false));
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = respond(&tables, request("GET", "/teams", None, "", true));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(log.lock().unwrap().is_empty());
+
+        let (status, body) = respond(
+            &tables,
+            request("GET", "/groups/{id}", Some("acme-support"), "", true),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "No such group");
+        assert_eq!(*log.lock().unwrap(), vec!["GetItem groups"]);
+    }
+
+    #[test]
+    fn test_invalid_group() {
+        let (tables, log) = dynamodb_stand_in(|_, _| (200, "{}".to_owned()));
+        let body = NEW_GROUP.replace("acme-support", "Acme Support");
+        let (status, body) = respond(&tables, request("POST", "/groups", None, &body, true));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["field"], "group_id");
+        assert!(log.lock().unwrap().is_empty());
+
+        let (status, _) = respond(&tables, request("POST", "/groups", None, "{", true));
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_group_conflicts() {
+        // The id is taken, so the number claimed for it is freed again
+        let (tables, log) = dynamodb_stand_in(|operation, table| match (operation, table) {
+            ("PutItem", "groups") => (400, CONDITION_FAILED.to_owned()),
+            _ => (200, "{}".to_owned()),
+        });
+        let (status, body) = respond(&tables, request("POST", "/groups", None, NEW_GROUP, true));
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "A group with this id already exists");
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "GetItem numbers",
+                "PutItem numbers",
+                "PutItem groups",
+                "DeleteItem numbers"
+            ]
+        );
+
+        // Another group claimed the number since it was checked
+        let (tables, log) = dynamodb_stand_in(|operation, table| match (operation, table) {
+            ("PutItem", "numbers") => (400, CONDITION_FAILED.to_owned()),
+            _ => (200, "{}".to_owned()),
+        });
+        let (status, body) = respond(&tables, request("POST", "/groups", None, NEW_GROUP, true));
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "+12183957949 belongs to another group");
+        assert!(!log.lock().unwrap().contains(&"PutItem groups".to_owned()));
+
+        // Someone else wrote the group since it was read
+        let (tables, _) = dynamodb_stand_in(|operation, table| {
+            match (operation, table) {
+            ("GetItem", "groups") => (
+                200,
+                r#"{"Item": {"group_id": {"S": "acme-support"}, "name": {"S": "Acme Support"}, "utc_offset": {"N": "-14400"}, "numbers": {"L": [{"S": "+12183957949"}]}, "members": {"L": []}, "greeting": {"NULL": true}, "version": {"N": "3"}}}"#.to_owned(),
+            ),
+            ("PutItem", "groups") => (400, CONDITION_FAILED.to_owned()),
+            _ => (200, "{}".to_owned()),
+        }
+        });
+        let (status, body) = respond(
+            &tables,
+            request("PUT", "/groups/{id}", Some("acme-support"), NEW_GROUP, true),
+        );
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "The group was changed by someone else");
+    }
+}
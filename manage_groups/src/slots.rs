@@ -0,0 +1,165 @@
+use crate::{error_response, invalid, json_response, parse_body, Tables};
+use chrono::{offset::FixedOffset, DateTime};
+use lambda_http::{http::StatusCode, Body, Request, Response};
+use lambda_runtime::error::HandlerError;
+use log::warn;
+use models::{
+    group::Group,
+    slot::{check_window, regenerate_schedule, GroupSlot},
+    time::TimeOfDayDuration,
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// Body of POST /groups/{id}/slots and PUT /groups/{id}/slots/{slot_id}
+#[derive(Deserialize, Debug)]
+struct SlotInput {
+    start: DateTime<FixedOffset>,
+    #[serde(default)]
+    end: Option<DateTime<FixedOffset>>,
+    restriction: TimeOfDayDuration,
+    provider_ids: Vec<String>,
+}
+
+/// Body of POST /groups/{id}/schedule
+#[derive(Deserialize, Debug)]
+struct WindowInput {
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+}
+
+async fn find_group(tables: &Tables, group_id: &str) -> Option<Group> {
+    Group::get_group(tables.groups.clone(), &tables.client, group_id.to_string()).await
+}
+
+/// /groups/{id}/slots and /groups/{id}/slots/{slot_id}
+pub async fn slots(
+    request: &Request,
+    tables: &Tables,
+    group_id: &str,
+    slot_id: Option<&str>,
+) -> Result<Response<Body>, HandlerError> {
+    let group = match find_group(tables, group_id).await {
+        Some(group) => group,
+        None => return error_response(StatusCode::NOT_FOUND, "No such group"),
+    };
+    let slot = match slot_id {
+        Some(slot_id) => {
+            let slot = match Uuid::parse_str(slot_id) {
+                Ok(slot_id) => {
+                    GroupSlot::get_slot(
+                        tables.slots.clone(),
+                        &tables.client,
+                        group.group_id.clone(),
+                        slot_id,
+                    )
+                    .await
+                }
+                Err(_e) => None,
+            };
+            match slot {
+                Some(slot) => Some(slot),
+                None => return error_response(StatusCode::NOT_FOUND, "No such slot"),
+            }
+        }
+        None => None,
+    };
+    match (request.method().as_str(), slot) {
+        ("GET", None) => {
+            let slots = GroupSlot::get_slots(tables.slots.clone(), &tables.client, group.group_id)
+                .await
+                .ok_or_else(|| HandlerError::from("SlotReadFail"))?;
+            json_response(StatusCode::OK, &slots)
+        }
+        ("POST", None) => {
+            let input: SlotInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            let slot = GroupSlot::new_group_slot(
+                group.group_id.clone(),
+                input.start,
+                input.end,
+                input.restriction,
+                input.provider_ids,
+            );
+            save_slot(tables, &group, &slot, StatusCode::CREATED).await
+        }
+        ("GET", Some(slot)) => json_response(StatusCode::OK, &slot),
+        ("PUT", Some(mut slot)) => {
+            let input: SlotInput = match parse_body(request) {
+                Ok(input) => input,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            };
+            slot.start = input.start;
+            slot.end = input.end;
+            slot.restriction = input.restriction;
+            slot.provider_ids = input.provider_ids;
+            save_slot(tables, &group, &slot, StatusCode::OK).await
+        }
+        ("DELETE", Some(slot)) => {
+            slot.delete_slot(tables.slots.clone(), &tables.client)
+                .await
+                .map_err(|_e| HandlerError::from("SlotDeleteFail"))?;
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::Empty)
+                .unwrap())
+        }
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/// Validates and stores a new or changed slot
+async fn save_slot(
+    tables: &Tables,
+    group: &Group,
+    slot: &GroupSlot,
+    status: StatusCode,
+) -> Result<Response<Body>, HandlerError> {
+    if let Err(e) = slot.validate(group) {
+        return invalid(e);
+    }
+    match slot.write_slot(tables.slots.clone(), &tables.client).await {
+        Ok(()) => json_response(status, slot),
+        Err(e) => {
+            warn!("Writing slot {} failed: {:?}", slot.slot_id, e);
+            Err(HandlerError::from("SlotWriteFail"))
+        }
+    }
+}
+
+/// POST /groups/{id}/schedule regenerates the group's schedule for a window
+/// from its slots, replacing the one calls are routed by
+pub async fn schedule(
+    request: &Request,
+    tables: &Tables,
+    group_id: &str,
+) -> Result<Response<Body>, HandlerError> {
+    if request.method().as_str() != "POST" {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+    let group = match find_group(tables, group_id).await {
+        Some(group) => group,
+        None => return error_response(StatusCode::NOT_FOUND, "No such group"),
+    };
+    let input: WindowInput = match parse_body(request) {
+        Ok(input) => input,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    if let Err(e) = check_window(input.start, input.end) {
+        return invalid(e);
+    }
+    let slots = GroupSlot::get_slots(tables.slots.clone(), &tables.client, group.group_id.clone())
+        .await
+        .ok_or_else(|| HandlerError::from("SlotReadFail"))?;
+    let schedule = regenerate_schedule(&group, &slots, input.start, input.end);
+    if let Err(e) = schedule
+        .async_write_schedule(tables.schedules.clone(), &tables.client)
+        .await
+    {
+        warn!("Writing schedule for {} failed: {:?}", group.group_id, e);
+        return Err(HandlerError::from("ScheduleWriteFail"));
+    }
+    json_response(StatusCode::OK, &schedule)
+}
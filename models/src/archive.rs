@@ -0,0 +1,292 @@
+use crate::call::Call;
+use hyper::{body, client::Client, header, Body, Request};
+use hyper_tls::HttpsConnector;
+use rusoto_core::{Region, RusotoError};
+use rusoto_credential::{AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials};
+use rusoto_s3::{
+    util::{PreSignedRequest, PreSignedRequestOption},
+    GetObjectRequest, PutObjectError, PutObjectRequest, S3Client, S3,
+};
+use std::time::Duration;
+
+/// How long a recording link sent in a page stays valid. Links signed with
+/// a Lambda role's session credentials stop working when the session does,
+/// whichever comes first.
+pub const SIGNED_URL_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Twilio serves recordings through a redirect to its own storage
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Download(String),
+    Upload(RusotoError<PutObjectError>),
+}
+
+/// Region to reach S3 in: AWS itself, or a local stand-in such as
+/// localstack listening at `endpoint`
+pub fn s3_region(endpoint: Option<String>) -> Region {
+    match endpoint {
+        Some(endpoint) => Region::Custom {
+            name: Region::UsEast1.name().to_string(),
+            endpoint,
+        },
+        None => Region::UsEast1,
+    }
+}
+
+/// Object key a call's recording is archived under. Repeat calls that join
+/// the call's incident are numbered from 1.
+pub fn recording_key(call: &Call, follow_up: usize) -> String {
+    match follow_up {
+        0 => format!("recordings/{}.mp3", call.call_id),
+        n => format!("recordings/{}-{}.mp3", call.call_id, n),
+    }
+}
+
+/// Downloads the Twilio recording at `message_url` and stores it in
+/// `bucket` under `key`. Twilio credentials are sent in case the account
+/// requires authentication to fetch media.
+pub async fn archive_recording(
+    s3_client: &S3Client,
+    message_url: &str,
+    key: String,
+    bucket: String,
+    twilio_sid: &str,
+    twilio_token: &str,
+) -> Result<String, ArchiveError> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let mut url = format!("{}.mp3", message_url);
+    let mut authorization = Some(format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", twilio_sid, twilio_token))
+    ));
+    let mut response = None;
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = Request::get(url.as_str());
+        if let Some(authorization) = &authorization {
+            request = request.header(header::AUTHORIZATION, authorization.as_str());
+        }
+        let request = request
+            .body(Body::empty())
+            .map_err(|e| ArchiveError::Download(e.to_string()))?;
+        let current = client
+            .request(request)
+            .await
+            .map_err(|e| ArchiveError::Download(e.to_string()))?;
+        if !current.status().is_redirection() {
+            response = Some(current);
+            break;
+        }
+        url = current
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| ArchiveError::Download("Redirect without a location".to_string()))?
+            .to_string();
+        // The redirect target is already signed and rejects other credentials
+        authorization = None;
+    }
+    let response =
+        response.ok_or_else(|| ArchiveError::Download("Too many redirects".to_string()))?;
+    if !response.status().is_success() {
+        return Err(ArchiveError::Download(format!(
+            "Recording returned {}",
+            response.status()
+        )));
+    }
+    let recording = body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| ArchiveError::Download(e.to_string()))?;
+
+    s3_client
+        .put_object(PutObjectRequest {
+            bucket,
+            key: key.clone(),
+            body: Some(recording.to_vec().into()),
+            content_type: Some("audio/mpeg".to_string()),
+            ..PutObjectRequest::default()
+        })
+        .await
+        .map_err(ArchiveError::Upload)?;
+    Ok(key)
+}
+
+/// Link to an archived object that opens without AWS credentials until
+/// `expires_in` has passed, signed with the ambient AWS credentials
+pub async fn signed_url(
+    bucket: String,
+    region: &Region,
+    key: String,
+    expires_in: Duration,
+) -> Option<String> {
+    let credentials = DefaultCredentialsProvider::new()
+        .ok()?
+        .credentials()
+        .await
+        .ok()?;
+    Some(presigned_url(bucket, region, &credentials, key, expires_in))
+}
+
+pub fn presigned_url(
+    bucket: String,
+    region: &Region,
+    credentials: &AwsCredentials,
+    key: String,
+    expires_in: Duration,
+) -> String {
+    GetObjectRequest {
+        bucket,
+        key,
+        ..GetObjectRequest::default()
+    }
+    .get_presigned_url(region, credentials, &PreSignedRequestOption { expires_in })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::HttpClient;
+    use rusoto_credential::StaticProvider;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio::runtime::Builder;
+    use uuid::Uuid;
+
+    /// Answers one HTTP request with `response` and hands back the request's
+    /// head and body
+    fn http_stand_in(response: String) -> (u16, thread::JoinHandle<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if header
+                    .next()
+                    .unwrap()
+                    .eq_ignore_ascii_case("content-length")
+                {
+                    length = header.next().unwrap().trim().parse().unwrap();
+                }
+                head.push_str(&line);
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            writer.write_all(response.as_bytes()).unwrap();
+            (head, body)
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_archive_recording() {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (media_port, media) = http_stand_in(
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nID3\x04\x00"
+                .to_owned(),
+        );
+        // Twilio redirects to its media storage
+        let (twilio_port, twilio) = http_stand_in(format!(
+            "HTTP/1.1 302 Found\r\nlocation: http://127.0.0.1:{}/media/RE123.mp3\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            media_port
+        ));
+        let (s3_port, s3) = http_stand_in(
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_owned(),
+        );
+        let s3_client = S3Client::new_with(
+            HttpClient::new().unwrap(),
+            StaticProvider::new_minimal("AKIDEXAMPLE".to_owned(), "secret".to_owned()),
+            s3_region(Some(format!("http://127.0.0.1:{}", s3_port))),
+        );
+        let archived = runtime.block_on(archive_recording(
+            &s3_client,
+            &format!("http://127.0.0.1:{}/Recordings/RE123", twilio_port),
+            "recordings/call.mp3".to_owned(),
+            "dev-recordings".to_owned(),
+            "AC123",
+            "token",
+        ));
+        assert_eq!(archived.unwrap(), "recordings/call.mp3");
+
+        let (head, _) = twilio.join().unwrap();
+        assert!(head.starts_with("GET /Recordings/RE123.mp3 "));
+        assert!(head.to_lowercase().contains("authorization: basic"));
+        let (head, _) = media.join().unwrap();
+        assert!(head.starts_with("GET /media/RE123.mp3 "));
+        assert!(!head.to_lowercase().contains("authorization"));
+        let (head, body) = s3.join().unwrap();
+        assert!(head.starts_with("PUT /dev-recordings/recordings/call.mp3 "));
+        assert!(head.to_lowercase().contains("content-type: audio/mpeg"));
+        assert_eq!(body, b"ID3\x04\x00");
+
+        // Nothing is uploaded when the recording can't be fetched
+        let (twilio_port, _twilio) = http_stand_in(
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_owned(),
+        );
+        let archived = runtime.block_on(archive_recording(
+            &s3_client,
+            &format!("http://127.0.0.1:{}/Recordings/RE404", twilio_port),
+            "recordings/call.mp3".to_owned(),
+            "dev-recordings".to_owned(),
+            "AC123",
+            "token",
+        ));
+        assert!(matches!(archived, Err(ArchiveError::Download(_))));
+    }
+
+    #[test]
+    fn test_recording_links() {
+        let call = Call::new_call(
+            Uuid::nil(),
+            "+12183957949".to_owned(),
+            "+12183957949".to_owned(),
+            "https://api.twilio.com/recording".to_owned(),
+            "+13473513315".to_owned(),
+            "2020-06-01T09:00:00Z".parse().unwrap(),
+        );
+        assert_eq!(
+            recording_key(&call, 2),
+            "recordings/00000000-0000-0000-0000-000000000000-2.mp3"
+        );
+        let key = recording_key(&call, 0);
+        assert_eq!(key, "recordings/00000000-0000-0000-0000-000000000000.mp3");
+
+        let credentials = AwsCredentials::new("AKIDEXAMPLE", "secret", None, None);
+        let url = presigned_url(
+            "dev-recordings".to_owned(),
+            &s3_region(None),
+            &credentials,
+            key.clone(),
+            SIGNED_URL_EXPIRY,
+        );
+        assert!(url.starts_with(
+            "https://s3.us-east-1.amazonaws.com/dev-recordings/recordings/00000000-0000-0000-0000-000000000000.mp3?"
+        ));
+        assert!(url.contains("X-Amz-Expires=86400"));
+        assert!(url.contains("X-Amz-Signature="));
+
+        let url = presigned_url(
+            "dev-recordings".to_owned(),
+            &s3_region(Some("http://localhost:4566".to_owned())),
+            &credentials,
+            key,
+            SIGNED_URL_EXPIRY,
+        );
+        assert!(url.starts_with("http://localhost:4566/dev-recordings/recordings/"));
+    }
+}
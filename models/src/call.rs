@@ -1,83 +1,998 @@
+use crate::escalation::TerminalAction;
+use crate::ivr::IvrOption;
+use crate::queue::EscalationMessage;
 use crate::users::User;
+use chrono::{DateTime, Duration, Utc};
 use dynomite::{
-    dynamodb::{DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput, PutItemOutput},
-    Item, FromAttributes, Attribute
+    dynamodb::{
+        AttributeValue, DynamoDb, DynamoDbClient, GetItemError, GetItemInput, PutItemError,
+        PutItemInput, PutItemOutput, QueryError, QueryInput,
+    },
+    error::AttributeError,
+    Attribute, Attributes, FromAttributes, Item,
 };
-use futures::Future;
 use rusoto_core::{Region, RusotoError};
+use rusoto_sqs::{SendMessageError, SendMessageResult, SqsClient};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, pin::Pin};
+use std::collections::HashMap;
 use uuid::Uuid;
-use rusoto_sqs::{
-    GetQueueUrlRequest, SendMessageError,
-    SendMessageRequest, SendMessageResult, Sqs, SqsClient,
-};
 
+/// Where a call is in its lifecycle. Only `Triggered` and `Notified` calls
+/// are still escalated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Attribute)]
+pub enum CallState {
+    Triggered,
+    Notified,
+    Acknowledged,
+    Resolved,
+    Expired,
+}
+
+impl CallState {
+    pub fn is_open(self) -> bool {
+        self == CallState::Triggered || self == CallState::Notified
+    }
+
+    fn can_become(self, next: CallState) -> bool {
+        match next {
+            CallState::Triggered => false,
+            CallState::Notified | CallState::Acknowledged | CallState::Expired => self.is_open(),
+            CallState::Resolved => self.is_open() || self == CallState::Acknowledged,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IllegalTransition {
+    pub from: CallState,
+    pub to: CallState,
+}
+
+/// One step in a call's lifecycle: who moved it to `state`, and when
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    pub state: CallState,
+    pub by: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl Attribute for Transition {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("state".to_string(), self.state.into_attr());
+        map.insert("by".to_string(), self.by.into_attr());
+        map.insert("at".to_string(), self.at.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(Transition {
+                    state: CallState::from_attr(field("state")?)?,
+                    by: Option::<String>::from_attr(field("by")?)?,
+                    at: DateTime::<Utc>::from_attr(field("at")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// What Twilio told us about the person who left the voicemail, beyond
+/// their number. Each field is only set when Twilio sent it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Caller {
+    /// Caller ID name, when caller name lookup is enabled on the number
+    pub name: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    /// Twilio's id for the inbound call
+    pub call_sid: Option<String>,
+    /// Length of the recording in seconds
+    pub recording_duration: Option<u32>,
+    pub received_at: Option<DateTime<Utc>>,
+}
+
+impl Caller {
+    /// Reads the caller fields of a Twilio voice webhook, which arrive as
+    /// empty strings when Twilio has nothing to report
+    pub fn from_webhook(params: &serde_json::Value, received_at: DateTime<Utc>) -> Caller {
+        let param = |name: &str| {
+            params[name]
+                .as_str()
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Caller {
+            name: param("CallerName"),
+            city: param("FromCity"),
+            state: param("FromState"),
+            call_sid: param("CallSid"),
+            recording_duration: param("RecordingDuration").and_then(|d| d.parse().ok()),
+            received_at: Some(received_at),
+        }
+    }
+
+    /// `number` followed by whatever else is known about who it belongs to,
+    /// e.g. "+13473513315 (JANE DOE, BROOKLYN, NY)"
+    pub fn describe(&self, number: &str) -> String {
+        let details: Vec<&str> = [&self.name, &self.city, &self.state]
+            .iter()
+            .filter_map(|detail| detail.as_deref())
+            .collect();
+        if details.is_empty() {
+            number.to_string()
+        } else {
+            format!("{} ({})", number, details.join(", "))
+        }
+    }
+}
+
+impl Attribute for Caller {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("name".to_string(), self.name.into_attr());
+        map.insert("city".to_string(), self.city.into_attr());
+        map.insert("state".to_string(), self.state.into_attr());
+        map.insert("call_sid".to_string(), self.call_sid.into_attr());
+        map.insert(
+            "recording_duration".to_string(),
+            self.recording_duration.into_attr(),
+        );
+        map.insert("received_at".to_string(), self.received_at.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| m.remove(name).unwrap_or_default();
+                Ok(Caller {
+                    name: Option::<String>::from_attr(field("name"))?,
+                    city: Option::<String>::from_attr(field("city"))?,
+                    state: Option::<String>::from_attr(field("state"))?,
+                    call_sid: Option::<String>::from_attr(field("call_sid"))?,
+                    recording_duration: Option::<u32>::from_attr(field("recording_duration"))?,
+                    received_at: Option::<DateTime<Utc>>::from_attr(field("received_at"))?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// A later call from the same number that joined this call's incident
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FollowUp {
+    /// The id the repeat call was given, which its transcription carries
+    pub call_id: Option<Uuid>,
+    pub message_url: String,
+    pub recording_key: Option<String>,
+    pub transcription: Option<String>,
+    pub caller: Caller,
+}
+
+impl Attribute for FollowUp {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("call_id".to_string(), self.call_id.into_attr());
+        map.insert("message_url".to_string(), self.message_url.into_attr());
+        map.insert("recording_key".to_string(), self.recording_key.into_attr());
+        map.insert("transcription".to_string(), self.transcription.into_attr());
+        map.insert("caller".to_string(), self.caller.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                // Follow-ups stored before a field was added read it as null
+                let mut field = |name: &str| {
+                    m.remove(name).unwrap_or(AttributeValue {
+                        null: Some(true),
+                        ..AttributeValue::default()
+                    })
+                };
+                Ok(FollowUp {
+                    call_id: Option::<Uuid>::from_attr(field("call_id"))?,
+                    message_url: String::from_attr(field("message_url"))?,
+                    recording_key: Option::<String>::from_attr(field("recording_key"))?,
+                    transcription: Option::<String>::from_attr(field("transcription"))?,
+                    caller: Caller::from_attr(field("caller"))?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Item)]
 pub struct Call {
     #[dynomite(partition_key)]
     pub call_id: Uuid,
     pub group_id: String,
+    /// The group number the call came in on, which pages are sent from
+    pub group_number: String,
     pub message_url: String,
+    /// The caller's number, i.e. the `From` of the inbound call
     pub phone_number: String,
+    pub caller: Caller,
+    /// Phone menu option the caller picked, if the group has a menu
+    pub menu_option: Option<IvrOption>,
+    /// Repeat calls from the same number while this one was open, oldest first
+    pub follow_ups: Vec<FollowUp>,
     pub users: Vec<User>,
-    pub handled: bool,
+    pub state: CallState,
+    /// Every state the call has been in, oldest first
+    pub transitions: Vec<Transition>,
+    /// Code responders quote when replying to a page, e.g. "ACK 4821"
+    pub short_code: String,
+    /// Escalation level that will be paged next
+    pub level: u32,
+    /// How many times `level` has already been repeated
+    pub repeat: u32,
+    /// Pages sent so far, across all levels
+    pub attempts: u32,
+    /// Seconds into the current step of the latest notification rules
+    /// delivered, so a redelivered reminder isn't sent twice
+    pub reminded: i64,
+    /// Set once the escalation policy ran out and its terminal action ran
+    pub terminal_action: Option<TerminalAction>,
+    /// Text of the voicemail, once Twilio has transcribed it
+    pub transcription: Option<String>,
+    /// Where the recording was archived in the recordings bucket, if it was
+    pub recording_key: Option<String>,
+    /// Bumped on every write. A write only succeeds if the stored call still
+    /// has the version it was read with.
+    pub version: u64,
+}
+
+/// Why `Call::get_call` returned no call
+#[derive(Debug)]
+pub enum ReadError {
+    NotFound,
+    /// DynamoDB could not be read, e.g. it throttled the request
+    Read(RusotoError<GetItemError>),
+    /// The stored item is not a call this code understands
+    Malformed(AttributeError),
 }
 
+/// Why `Call::update_call` gave up
+#[derive(Debug)]
+pub enum UpdateError<E> {
+    NotFound,
+    Read(ReadError),
+    /// The change itself refused to apply to the stored call
+    Rejected(E),
+    /// Other writers kept getting in first
+    Conflict,
+    Write(RusotoError<PutItemError>),
+}
+
+/// How many times `Call::update_call` re-reads a call after losing a race
+pub const MAX_UPDATE_ATTEMPTS: u32 = 5;
+
+/// Index of the call table by `short_code`, which SMS replies quote
+pub const SHORT_CODE_INDEX: &str = "short_code-index";
+
+/// Index of the call table by `group_id` and the caller's `phone_number`
+pub const CALLER_INDEX: &str = "caller-index";
+
 impl Call {
-    #[tokio::main]
-    pub async fn async_write_call<'a>(
-        &self,
-        client: &'a DynamoDbClient,
+    pub fn new_call(
+        call_id: Uuid,
+        group_id: String,
+        group_number: String,
+        message_url: String,
+        phone_number: String,
+        at: DateTime<Utc>,
+    ) -> Call {
+        Call {
+            call_id,
+            group_id,
+            group_number,
+            message_url,
+            phone_number,
+            caller: Caller {
+                received_at: Some(at),
+                ..Caller::default()
+            },
+            menu_option: None,
+            follow_ups: Vec::new(),
+            users: Vec::new(),
+            state: CallState::Triggered,
+            transitions: vec![Transition {
+                state: CallState::Triggered,
+                by: None,
+                at,
+            }],
+            short_code: Call::new_short_code(&call_id),
+            level: 0,
+            repeat: 0,
+            attempts: 0,
+            reminded: 0,
+            terminal_action: None,
+            transcription: None,
+            recording_key: None,
+            version: 0,
+        }
+    }
+
+    pub fn new_short_code(call_id: &Uuid) -> String {
+        format!("{:04}", call_id.as_u128() % 10000)
+    }
+
+    fn transition(
+        &mut self,
+        to: CallState,
+        by: Option<String>,
+        at: DateTime<Utc>,
+    ) -> Result<(), IllegalTransition> {
+        if !self.state.can_become(to) {
+            return Err(IllegalTransition {
+                from: self.state,
+                to,
+            });
+        }
+        self.state = to;
+        self.transitions.push(Transition { state: to, by, at });
+        Ok(())
+    }
+
+    /// Records that the call's current users were paged. Calls are re-notified
+    /// on every escalation step, so this is allowed from `Notified` too.
+    pub fn notify(&mut self, at: DateTime<Utc>) -> Result<(), IllegalTransition> {
+        self.transition(CallState::Notified, None, at)
+    }
+
+    pub fn acknowledge(&mut self, by: String, at: DateTime<Utc>) -> Result<(), IllegalTransition> {
+        self.transition(CallState::Acknowledged, Some(by), at)
+    }
+
+    pub fn resolve(&mut self, by: String, at: DateTime<Utc>) -> Result<(), IllegalTransition> {
+        self.transition(CallState::Resolved, Some(by), at)
+    }
+
+    pub fn expire(&mut self, at: DateTime<Utc>) -> Result<(), IllegalTransition> {
+        self.transition(CallState::Expired, None, at)
+    }
+
+    /// Key of the schedule and escalation policy the call is paged by: the
+    /// menu option's route, or else the group's own
+    pub fn route(&self) -> &str {
+        match &self.menu_option {
+            Some(option) => &option.route,
+            None => &self.group_id,
+        }
+    }
+
+    /// Recordings that haven't been archived yet, numbered as in
+    /// `recording_key`: 0 for the call's own, then its follow-ups from 1
+    pub fn unarchived_recordings(&self) -> Vec<(usize, String)> {
+        let own = Some((0, &self.message_url)).filter(|_| self.recording_key.is_none());
+        own.into_iter()
+            .chain(
+                self.follow_ups
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, follow_up)| follow_up.recording_key.is_none())
+                    .map(|(i, follow_up)| (i + 1, &follow_up.message_url)),
+            )
+            .map(|(number, url)| (number, url.clone()))
+            .collect()
+    }
+
+    /// Records where recording `number` was archived, numbered as in
+    /// `unarchived_recordings`
+    pub fn set_recording_key(&mut self, number: usize, key: String) {
+        match number {
+            0 => self.recording_key = Some(key),
+            n => {
+                if let Some(follow_up) = self.follow_ups.get_mut(n - 1) {
+                    follow_up.recording_key = Some(key);
+                }
+            }
+        }
+    }
+
+    /// Stores the transcription of the repeat call that was given `call_id`,
+    /// returning whether it joined this call's incident
+    pub fn transcribe_follow_up(&mut self, call_id: Uuid, text: String) -> bool {
+        match self
+            .follow_ups
+            .iter_mut()
+            .find(|follow_up| follow_up.call_id == Some(call_id))
+        {
+            Some(follow_up) => {
+                follow_up.transcription = Some(text);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the caller was last heard from, on this call or a follow-up
+    pub fn last_heard(&self) -> Option<DateTime<Utc>> {
+        self.follow_ups
+            .iter()
+            .rev()
+            .find_map(|follow_up| follow_up.caller.received_at)
+            .or(self.caller.received_at)
+            .or_else(|| self.transitions.first().map(|transition| transition.at))
+    }
+
+    /// Whether a call arriving `at` from the same number belongs to this
+    /// call's incident rather than starting a new one. Acknowledged calls
+    /// still count as the incident is being worked on.
+    pub fn absorbs_repeat(&self, at: DateTime<Utc>, window: Duration) -> bool {
+        if matches!(self.state, CallState::Resolved | CallState::Expired)
+            || window <= Duration::zero()
+        {
+            return false;
+        }
+        match self.last_heard() {
+            Some(last_heard) => at - last_heard <= window,
+            None => false,
+        }
+    }
+
+    /// Reads a stored call, including ones written before calls had a state,
+    /// short code or escalation level. Their `handled` flag becomes
+    /// `Acknowledged` (or `Triggered` if unset) with no recorded transitions.
+    pub fn from_stored(mut attrs: Attributes) -> Result<Call, AttributeError> {
+        if !attrs.contains_key("state") {
+            let handled = match attrs.remove("handled") {
+                Some(value) => bool::from_attr(value)?,
+                None => false,
+            };
+            let state = if handled {
+                CallState::Acknowledged
+            } else {
+                CallState::Triggered
+            };
+            attrs.insert("state".to_string(), state.into_attr());
+        }
+        if !attrs.contains_key("transitions") {
+            attrs.insert(
+                "transitions".to_string(),
+                Vec::<Transition>::new().into_attr(),
+            );
+        }
+        if !attrs.contains_key("short_code") {
+            let call_id = Uuid::from_attr(attrs.get("call_id").cloned().ok_or(
+                AttributeError::MissingField {
+                    name: "call_id".to_string(),
+                },
+            )?)?;
+            attrs.insert(
+                "short_code".to_string(),
+                Call::new_short_code(&call_id).into_attr(),
+            );
+        }
+        for counter in &["level", "repeat", "attempts"] {
+            if !attrs.contains_key(*counter) {
+                attrs.insert(counter.to_string(), 0u32.into_attr());
+            }
+        }
+        // Groups were keyed by their number before they had ids of their own
+        if !attrs.contains_key("group_number") {
+            let group_id = attrs
+                .get("group_id")
+                .cloned()
+                .ok_or(AttributeError::MissingField {
+                    name: "group_id".to_string(),
+                })?;
+            attrs.insert("group_number".to_string(), group_id);
+        }
+        if !attrs.contains_key("reminded") {
+            attrs.insert("reminded".to_string(), 0i64.into_attr());
+        }
+        if !attrs.contains_key("terminal_action") {
+            attrs.insert(
+                "terminal_action".to_string(),
+                Option::<TerminalAction>::None.into_attr(),
+            );
+        }
+        if !attrs.contains_key("caller") {
+            attrs.insert("caller".to_string(), Caller::default().into_attr());
+        }
+        if !attrs.contains_key("menu_option") {
+            attrs.insert(
+                "menu_option".to_string(),
+                Option::<IvrOption>::None.into_attr(),
+            );
+        }
+        if !attrs.contains_key("follow_ups") {
+            attrs.insert("follow_ups".to_string(), Vec::<FollowUp>::new().into_attr());
+        }
+        for optional in &["transcription", "recording_key"] {
+            if !attrs.contains_key(*optional) {
+                attrs.insert(optional.to_string(), Option::<String>::None.into_attr());
+            }
+        }
+        if !attrs.contains_key("version") {
+            attrs.insert("version".to_string(), 0u64.into_attr());
+        }
+        Call::from_attrs(attrs)
+    }
+
+    /// Writes the call and bumps its version, failing with
+    /// `PutItemError::ConditionalCheckFailed` if someone else wrote it since
+    /// it was read
+    pub async fn async_write_call(
+        &mut self,
+        client: &DynamoDbClient,
         table_name: String,
-    ) -> Pin<Box<dyn Future<Output = Result<PutItemOutput, RusotoError<PutItemError>>> + 'a>> {
-        client.put_item(PutItemInput {
-            table_name,
-            item: self.clone().into(), // <= convert schedule into it's attribute map representation
-            ..PutItemInput::default()
-        })
+    ) -> Result<PutItemOutput, RusotoError<PutItemError>> {
+        let read_version = self.version;
+        let mut values = HashMap::new();
+        // Version 0 covers new calls and ones stored before calls were versioned
+        let condition = if read_version == 0 {
+            "attribute_not_exists(version)"
+        } else {
+            values.insert(":version".to_string(), read_version.into_attr());
+            "version = :version"
+        };
+        self.version += 1;
+        let output = client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(), // <= convert schedule into it's attribute map representation
+                condition_expression: Some(condition.to_string()),
+                expression_attribute_values: if values.is_empty() {
+                    None
+                } else {
+                    Some(values)
+                },
+                ..PutItemInput::default()
+            })
+            .await;
+        if output.is_err() {
+            self.version = read_version;
+        }
+        output
+    }
+
+    /// Reads the call, applies `change` and writes it back, starting over
+    /// from a fresh read whenever another writer got in first
+    pub async fn update_call<T, E, F>(
+        table_name: String,
+        region: Region,
+        key: String,
+        mut change: F,
+    ) -> Result<(Call, T), UpdateError<E>>
+    where
+        F: FnMut(&mut Call) -> Result<T, E>,
+    {
+        let client = DynamoDbClient::new(region.clone());
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let mut call =
+                match Call::get_call(table_name.clone(), region.clone(), key.clone()).await {
+                    Ok(call) => call,
+                    Err(ReadError::NotFound) => return Err(UpdateError::NotFound),
+                    Err(e) => return Err(UpdateError::Read(e)),
+                };
+            let value = change(&mut call).map_err(UpdateError::Rejected)?;
+            match call.async_write_call(&client, table_name.clone()).await {
+                Ok(_) => return Ok((call, value)),
+                Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => continue,
+                Err(e) => return Err(UpdateError::Write(e)),
+            }
+        }
+        Err(UpdateError::Conflict)
     }
 
-    #[tokio::main]
-    pub async fn get_call(table_name: String, region: Region, key: String) -> Option<Call> {
+    pub async fn get_call(
+        table_name: String,
+        region: Region,
+        key: String,
+    ) -> Result<Call, ReadError> {
         let client = DynamoDbClient::new(region);
         let mut key_map = HashMap::new();
         key_map.insert("call_id".to_string(), key.into_attr());
-        client
+        let attrs = client
             .get_item(GetItemInput {
                 table_name,
                 key: key_map,
                 ..GetItemInput::default()
             })
             .await
-            .ok()
-            .map_or_else(|| None, |output| output.item)
-            .map_or_else(|| None, |attrs| Call::from_attrs(attrs).ok())
-        
+            .map_err(ReadError::Read)?
+            .item
+            .ok_or(ReadError::NotFound)?;
+        Call::from_stored(attrs).map_err(ReadError::Malformed)
+    }
+
+    /// Calls that are not yet resolved or expired whose short code matches
+    pub async fn get_open_calls(
+        table_name: String,
+        region: Region,
+        short_code: String,
+    ) -> Result<Vec<Call>, RusotoError<QueryError>> {
+        let mut values = HashMap::new();
+        values.insert(":short_code".to_string(), short_code.into_attr());
+        Call::query_calls(
+            table_name,
+            region,
+            SHORT_CODE_INDEX,
+            "short_code = :short_code",
+            values,
+            true,
+        )
+        .await
+    }
+
+    /// Calls from `phone_number` to `group_id` that are not yet resolved or
+    /// expired
+    pub async fn get_open_calls_from(
+        table_name: String,
+        region: Region,
+        group_id: String,
+        phone_number: String,
+    ) -> Result<Vec<Call>, RusotoError<QueryError>> {
+        let mut values = HashMap::new();
+        values.insert(":group_id".to_string(), group_id.into_attr());
+        values.insert(":phone_number".to_string(), phone_number.into_attr());
+        Call::query_calls(
+            table_name,
+            region,
+            CALLER_INDEX,
+            "group_id = :group_id AND phone_number = :phone_number",
+            values,
+            true,
+        )
+        .await
+    }
+
+    /// Every call from `phone_number` to `group_id`, whatever its state
+    pub async fn get_calls_from(
+        table_name: String,
+        region: Region,
+        group_id: String,
+        phone_number: String,
+    ) -> Result<Vec<Call>, RusotoError<QueryError>> {
+        let mut values = HashMap::new();
+        values.insert(":group_id".to_string(), group_id.into_attr());
+        values.insert(":phone_number".to_string(), phone_number.into_attr());
+        Call::query_calls(
+            table_name,
+            region,
+            CALLER_INDEX,
+            "group_id = :group_id AND phone_number = :phone_number",
+            values,
+            false,
+        )
+        .await
     }
 
-    #[tokio::main]
-    pub async fn sqs_push<'a>(
+    /// Queries `index_name`, keeping only calls not yet resolved or expired
+    /// if `open_only`
+    async fn query_calls(
+        table_name: String,
+        region: Region,
+        index_name: &str,
+        key_condition: &str,
+        mut values: HashMap<String, AttributeValue>,
+        open_only: bool,
+    ) -> Result<Vec<Call>, RusotoError<QueryError>> {
+        let client = DynamoDbClient::new(region);
+        let (filter, names) = if open_only {
+            let mut names = HashMap::new();
+            names.insert("#state".to_string(), "state".to_string());
+            values.insert(":triggered".to_string(), CallState::Triggered.into_attr());
+            values.insert(":notified".to_string(), CallState::Notified.into_attr());
+            values.insert(
+                ":acknowledged".to_string(),
+                CallState::Acknowledged.into_attr(),
+            );
+            (
+                Some("#state IN (:triggered, :notified, :acknowledged)".to_string()),
+                Some(names),
+            )
+        } else {
+            (None, None)
+        };
+        let mut calls = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = client
+                .query(QueryInput {
+                    table_name: table_name.clone(),
+                    index_name: Some(index_name.to_string()),
+                    key_condition_expression: Some(key_condition.to_string()),
+                    filter_expression: filter.clone(),
+                    expression_attribute_names: names.clone(),
+                    expression_attribute_values: Some(values.clone()),
+                    exclusive_start_key: start_key,
+                    ..QueryInput::default()
+                })
+                .await?;
+            calls.extend(
+                output
+                    .items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|attrs| Call::from_stored(attrs).ok()),
+            );
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(calls);
+            }
+        }
+    }
+
+    /// Queues the call's next escalation step on `queue_url` in
+    /// `delay_time` seconds, however long
+    pub async fn sqs_push(
         &self,
-        sqs_client: &'a SqsClient,
+        sqs_client: &SqsClient,
+        queue_url: String,
         delay_time: i64,
-    ) -> Pin<Box<dyn Future<Output = Result<SendMessageResult, RusotoError<SendMessageError>>> + 'a>> {
-        let call_id = self.call_id;
-        let group_id = self.group_id.clone();
-        sqs_client.send_message(SendMessageRequest {
-            delay_seconds: Some(delay_time),
-            message_body: call_id.to_string(),
-            queue_url: sqs_client
-                .get_queue_url(GetQueueUrlRequest {
-                    queue_name: group_id,
-                    queue_owner_aws_account_id: None,
-                })
-                .await
-                .unwrap()
-                .queue_url
-                .unwrap(),
-            ..SendMessageRequest::default()
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        EscalationMessage::new_message(self, Utc::now() + Duration::seconds(delay_time))
+            .send(sqs_client, queue_url)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_call() -> Call {
+        Call::new_call(
+            Uuid::nil(),
+            "+12183957949".to_owned(),
+            "+12183957949".to_owned(),
+            "https://api.twilio.com/recording".to_owned(),
+            "+13473513315".to_owned(),
+            "2020-06-01T09:00:00Z".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut call = test_call();
+        let at: DateTime<Utc> = "2020-06-01T09:01:00Z".parse().unwrap();
+        assert_eq!(call.state, CallState::Triggered);
+        assert!(call.notify(at).is_ok());
+        assert!(call.notify(at).is_ok());
+        assert!(call.acknowledge("+19149543303".to_owned(), at).is_ok());
+        assert_eq!(
+            call.acknowledge("+19147251309".to_owned(), at),
+            Err(IllegalTransition {
+                from: CallState::Acknowledged,
+                to: CallState::Acknowledged
+            })
+        );
+        assert!(call.expire(at).is_err());
+        assert!(call.resolve("+19147251309".to_owned(), at).is_ok());
+        assert!(call.resolve("+19147251309".to_owned(), at).is_err());
+        assert_eq!(call.state, CallState::Resolved);
+        assert_eq!(
+            call.transitions.iter().map(|t| t.state).collect::<Vec<_>>(),
+            vec![
+                CallState::Triggered,
+                CallState::Notified,
+                CallState::Notified,
+                CallState::Acknowledged,
+                CallState::Resolved
+            ]
+        );
+        assert_eq!(call.transitions[3].by, Some("+19149543303".to_owned()));
+
+        let mut call = test_call();
+        assert!(call.expire(at).is_ok());
+        assert!(call.notify(at).is_err());
+        assert!(call.resolve("+19149543303".to_owned(), at).is_err());
+    }
+
+    fn test_follow_up(call_id: Uuid, at: DateTime<Utc>) -> FollowUp {
+        FollowUp {
+            call_id: Some(call_id),
+            message_url: "https://api.twilio.com/recording2".to_owned(),
+            recording_key: None,
+            transcription: None,
+            caller: Caller {
+                received_at: Some(at),
+                ..Caller::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_absorbs_repeat() {
+        let mut call = test_call();
+        call.caller.received_at = None;
+        let window = Duration::minutes(10);
+        let at: DateTime<Utc> = "2020-06-01T09:08:00Z".parse().unwrap();
+        assert_eq!(
+            call.last_heard(),
+            Some("2020-06-01T09:00:00Z".parse().unwrap())
+        );
+        assert!(call.absorbs_repeat(at, window));
+        assert!(!call.absorbs_repeat(at, Duration::zero()));
+        assert!(!call.absorbs_repeat("2020-06-01T09:11:00Z".parse().unwrap(), window));
+
+        // The window slides with each repeat call
+        call.follow_ups.push(test_follow_up(Uuid::new_v4(), at));
+        assert!(call.absorbs_repeat("2020-06-01T09:11:00Z".parse().unwrap(), window));
+
+        call.acknowledge("+19149543303".to_owned(), at).unwrap();
+        assert!(call.absorbs_repeat("2020-06-01T09:11:00Z".parse().unwrap(), window));
+        call.resolve("+19149543303".to_owned(), at).unwrap();
+        assert!(!call.absorbs_repeat("2020-06-01T09:11:00Z".parse().unwrap(), window));
+    }
+
+    #[test]
+    fn test_stored_follow_ups() {
+        let mut call = test_call();
+        let at: DateTime<Utc> = "2020-06-01T09:08:00Z".parse().unwrap();
+        call.follow_ups.push(test_follow_up(Uuid::new_v4(), at));
+        let attrs: Attributes = call.clone().into();
+        assert_eq!(
+            Call::from_stored(attrs).unwrap().follow_ups,
+            call.follow_ups
+        );
+
+        // Follow-ups stored before ids and transcriptions were kept
+        let mut old = HashMap::new();
+        old.insert(
+            "message_url".to_string(),
+            "https://api.twilio.com/recording3".to_string().into_attr(),
+        );
+        old.insert("caller".to_string(), Caller::default().into_attr());
+        let old = FollowUp::from_attr(AttributeValue {
+            m: Some(old),
+            ..AttributeValue::default()
         })
+        .unwrap();
+        assert_eq!(old.call_id, None);
+        assert_eq!(old.transcription, None);
+    }
+
+    #[test]
+    fn test_recording_keys() {
+        let mut call = test_call();
+        let at: DateTime<Utc> = "2020-06-01T09:08:00Z".parse().unwrap();
+        call.follow_ups.push(test_follow_up(Uuid::new_v4(), at));
+        assert_eq!(
+            call.unarchived_recordings(),
+            vec![
+                (0, "https://api.twilio.com/recording".to_owned()),
+                (1, "https://api.twilio.com/recording2".to_owned())
+            ]
+        );
+        call.set_recording_key(1, "recordings/nil-1.mp3".to_owned());
+        assert_eq!(
+            call.follow_ups[0].recording_key.as_deref(),
+            Some("recordings/nil-1.mp3")
+        );
+        call.set_recording_key(0, "recordings/nil.mp3".to_owned());
+        assert!(call.unarchived_recordings().is_empty());
+    }
+
+    #[test]
+    fn test_transcribe_follow_up() {
+        let mut call = test_call();
+        let repeat_id = Uuid::new_v4();
+        let at: DateTime<Utc> = "2020-06-01T09:08:00Z".parse().unwrap();
+        call.follow_ups.push(test_follow_up(repeat_id, at));
+        assert!(!call.transcribe_follow_up(Uuid::new_v4(), "Hello?".to_owned()));
+        assert!(call.transcribe_follow_up(repeat_id, "Still down".to_owned()));
+        assert_eq!(
+            call.follow_ups[0].transcription.as_deref(),
+            Some("Still down")
+        );
+    }
+
+    #[test]
+    fn test_caller() {
+        let params = serde_json::json!({
+            "CallSid": "CA123",
+            "From": "+13473513315",
+            "CallerName": "JANE DOE",
+            "FromCity": "BROOKLYN",
+            "FromState": "NY",
+            "RecordingDuration": "42"
+        });
+        let at: DateTime<Utc> = "2020-06-01T09:00:00Z".parse().unwrap();
+        let caller = Caller::from_webhook(&params, at);
+        assert_eq!(
+            caller,
+            Caller {
+                name: Some("JANE DOE".to_owned()),
+                city: Some("BROOKLYN".to_owned()),
+                state: Some("NY".to_owned()),
+                call_sid: Some("CA123".to_owned()),
+                recording_duration: Some(42),
+                received_at: Some(at),
+            }
+        );
+        assert_eq!(
+            caller.describe("+13473513315"),
+            "+13473513315 (JANE DOE, BROOKLYN, NY)"
+        );
+        assert_eq!(
+            Caller::from_attr(caller.clone().into_attr()).unwrap(),
+            caller
+        );
+
+        let caller =
+            Caller::from_webhook(&serde_json::json!({"CallerName": "", "FromCity": ""}), at);
+        assert_eq!(caller.name, None);
+        assert_eq!(caller.describe("+13473513315"), "+13473513315");
+    }
+
+    #[test]
+    fn test_from_stored() {
+        let call = test_call();
+        let attrs: Attributes = call.clone().into();
+        let read = Call::from_stored(attrs).unwrap();
+        assert_eq!(read.transitions, call.transitions);
+        let mut transition = call.transitions[0].clone().into_attr();
+        transition.m.as_mut().unwrap().remove("at");
+        assert!(matches!(
+            Transition::from_attr(transition),
+            Err(AttributeError::MissingField { name }) if name == "at"
+        ));
+
+        // Items written while calls only had a handled flag
+        let mut attrs: Attributes = call.into();
+        for name in &[
+            "group_number",
+            "state",
+            "transitions",
+            "short_code",
+            "level",
+            "repeat",
+            "attempts",
+            "reminded",
+            "terminal_action",
+            "caller",
+            "menu_option",
+            "follow_ups",
+            "transcription",
+            "recording_key",
+            "version",
+        ] {
+            attrs.remove(*name);
+        }
+        attrs.insert("handled".to_string(), true.into_attr());
+        let read = Call::from_stored(attrs.clone()).unwrap();
+        assert_eq!(read.state, CallState::Acknowledged);
+        assert!(read.transitions.is_empty());
+        assert_eq!(read.short_code, "0000");
+        assert_eq!(read.group_number, read.group_id);
+        assert_eq!(read.level, 0);
+        assert_eq!(read.reminded, 0);
+        assert_eq!(read.terminal_action, None);
+        assert_eq!(read.transcription, None);
+        assert_eq!(read.recording_key, None);
+        assert_eq!(read.caller, Caller::default());
+        assert!(read.follow_ups.is_empty());
+        assert_eq!(read.menu_option, None);
+        assert_eq!(read.version, 0);
+
+        attrs.insert("handled".to_string(), false.into_attr());
+        assert_eq!(
+            Call::from_stored(attrs).unwrap().state,
+            CallState::Triggered
+        );
     }
 }
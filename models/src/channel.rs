@@ -0,0 +1,404 @@
+use crate::call::Call;
+use crate::notify::{sms_body, Notifier, NotifyError};
+use async_trait::async_trait;
+use dynomite::Attribute;
+use hyper::{client::HttpConnector, header, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use lettre::{
+    smtp::{authentication::Credentials, error::Error as SmtpError},
+    ClientSecurity, ClientTlsParameters, SmtpClient, Transport,
+};
+use lettre_email::EmailBuilder;
+use native_tls::TlsConnector;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Attribute)]
+pub enum ChannelKind {
+    Sms,
+    Voice,
+    Email,
+    /// An HTTP endpoint taking a JSON post, such as a chat incoming-webhook
+    Webhook,
+}
+
+/// Everything a channel might need to tell a user about a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub call_id: Uuid,
+    /// The group number pages are sent from
+    pub from: String,
+    pub subject: String,
+    pub text: String,
+    /// TwiML read out when a voice page is answered
+    pub page_url: String,
+}
+
+impl Page {
+    pub fn new_page(call: &Call, page_url: &str, recording_url: &str) -> Page {
+        Page {
+            call_id: call.call_id,
+            from: call.group_number.clone(),
+            subject: format!("Page {} for {}", call.short_code, call.group_id),
+            text: sms_body(call, recording_url),
+            page_url: page_url.to_string(),
+        }
+    }
+}
+
+/// One way of reaching a user. `address` is whatever the channel delivers
+/// to: a phone number, an email address or a URL.
+#[async_trait]
+pub trait NotificationChannel {
+    fn kind(&self) -> ChannelKind;
+    async fn deliver(&self, address: &str, page: &Page) -> Result<(), NotifyError>;
+}
+
+/// Texts the page from the group number
+pub struct SmsChannel<'a, N> {
+    notifier: &'a N,
+}
+
+impl<'a, N: Notifier + Sync> SmsChannel<'a, N> {
+    pub fn new(notifier: &'a N) -> SmsChannel<'a, N> {
+        SmsChannel { notifier }
+    }
+}
+
+#[async_trait]
+impl<'a, N: Notifier + Sync> NotificationChannel for SmsChannel<'a, N> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::Sms
+    }
+
+    async fn deliver(&self, address: &str, page: &Page) -> Result<(), NotifyError> {
+        self.notifier
+            .send_sms(&page.from, address, &page.text)
+            .await
+    }
+}
+
+/// Rings the user from the group number and reads out the page
+pub struct VoiceChannel<'a, N> {
+    notifier: &'a N,
+}
+
+impl<'a, N: Notifier + Sync> VoiceChannel<'a, N> {
+    pub fn new(notifier: &'a N) -> VoiceChannel<'a, N> {
+        VoiceChannel { notifier }
+    }
+}
+
+#[async_trait]
+impl<'a, N: Notifier + Sync> NotificationChannel for VoiceChannel<'a, N> {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::Voice
+    }
+
+    async fn deliver(&self, address: &str, page: &Page) -> Result<(), NotifyError> {
+        self.notifier
+            .place_call(&page.from, address, &page.page_url)
+            .await
+    }
+}
+
+/// Mail server pages are sent through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    /// Upgrade the connection with STARTTLS; only a local stand-in should go without
+    pub starttls: bool,
+    pub credentials: Option<(String, String)>,
+    /// Address pages are sent from
+    pub from: String,
+}
+
+/// Emails the page
+pub struct EmailChannel {
+    config: SmtpConfig,
+}
+
+impl EmailChannel {
+    pub fn new(config: SmtpConfig) -> EmailChannel {
+        EmailChannel { config }
+    }
+
+    fn send(config: SmtpConfig, address: String, page: Page) -> Result<(), NotifyError> {
+        let email = EmailBuilder::new()
+            .from(config.from.as_str())
+            .to(address.as_str())
+            .subject(page.subject.as_str())
+            .text(page.text.as_str())
+            .build()
+            .map_err(|e| NotifyError::Client(e.to_string()))?;
+        let security = if config.starttls {
+            let connector = TlsConnector::new().map_err(|e| NotifyError::Client(e.to_string()))?;
+            ClientSecurity::Required(ClientTlsParameters::new(config.host.clone(), connector))
+        } else {
+            ClientSecurity::None
+        };
+        let mut client =
+            SmtpClient::new((config.host.as_str(), config.port), security).map_err(smtp_error)?;
+        if let Some((username, password)) = config.credentials {
+            client = client.credentials(Credentials::new(username, password));
+        }
+        client
+            .transport()
+            .send(email.into())
+            .map(|_response| ())
+            .map_err(smtp_error)
+    }
+}
+
+fn smtp_error(e: SmtpError) -> NotifyError {
+    match e {
+        SmtpError::Transient(response) | SmtpError::Permanent(response) => NotifyError::Rejected {
+            code: response.code.to_string().parse().unwrap_or_default(),
+            message: response.message.join(" "),
+        },
+        e => NotifyError::Client(e.to_string()),
+    }
+}
+
+#[async_trait]
+impl NotificationChannel for EmailChannel {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::Email
+    }
+
+    async fn deliver(&self, address: &str, page: &Page) -> Result<(), NotifyError> {
+        // The SMTP client blocks, so it gets a thread of its own
+        let (config, address, page) = (self.config.clone(), address.to_string(), page.clone());
+        tokio::task::spawn_blocking(move || EmailChannel::send(config, address, page))
+            .await
+            .map_err(|e| NotifyError::Client(e.to_string()))?
+    }
+}
+
+/// Posts the page as JSON with the text under `text`, the shape Slack and
+/// Mattermost incoming-webhooks expect
+pub struct WebhookChannel {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl WebhookChannel {
+    pub fn new() -> WebhookChannel {
+        WebhookChannel {
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+}
+
+impl Default for WebhookChannel {
+    fn default() -> Self {
+        WebhookChannel::new()
+    }
+}
+
+#[async_trait]
+impl NotificationChannel for WebhookChannel {
+    fn kind(&self) -> ChannelKind {
+        ChannelKind::Webhook
+    }
+
+    async fn deliver(&self, address: &str, page: &Page) -> Result<(), NotifyError> {
+        let body = json!({
+            "text": page.text,
+            "call_id": page.call_id,
+        });
+        let request = Request::post(address)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| NotifyError::Client(e.to_string()))?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| NotifyError::Client(e.to_string()))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(NotifyError::Rejected {
+                code: response.status().as_u16() as usize,
+                message: response
+                    .status()
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .to_string(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio::runtime::Builder;
+
+    fn test_page() -> Page {
+        Page {
+            call_id: Uuid::nil(),
+            from: "+12183957949".to_owned(),
+            subject: "Page 4821 for +12183957949".to_owned(),
+            text: "New page for +12183957949".to_owned(),
+            page_url: "https://example.com/page_call".to_owned(),
+        }
+    }
+
+    /// Accepts one SMTP session, answering every command with `reply` once
+    /// the envelope starts, and hands back what was sent
+    fn smtp_stand_in(reply: &'static str) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut transcript = String::new();
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                transcript.push_str(&line);
+                let command = line.to_uppercase();
+                let response = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    "250 queued"
+                } else if command.starts_with("EHLO") {
+                    "250 localhost"
+                } else if command.starts_with("MAIL") {
+                    reply
+                } else if command.starts_with("RCPT") {
+                    "250 OK"
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    "354 go ahead"
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    "250 OK"
+                };
+                writer
+                    .write_all(format!("{}\r\n", response).as_bytes())
+                    .unwrap();
+            }
+            transcript
+        });
+        (port, handle)
+    }
+
+    /// Answers one HTTP request with `status` and hands back its body
+    fn webhook_stand_in(status: &'static str) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if header
+                    .next()
+                    .unwrap()
+                    .eq_ignore_ascii_case("content-length")
+                {
+                    length = header.next().unwrap().trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            writer
+                .write_all(format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status).as_bytes())
+                .unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (port, handle)
+    }
+
+    fn smtp_config(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            starttls: false,
+            credentials: None,
+            from: "pager@example.com".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_email_channel() {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (port, stand_in) = smtp_stand_in("250 OK");
+        let channel = EmailChannel::new(smtp_config(port));
+        assert_eq!(channel.kind(), ChannelKind::Email);
+        let result = runtime.block_on(channel.deliver("jeff@example.com", &test_page()));
+        assert_eq!(result, Ok(()));
+        let transcript = stand_in.join().unwrap();
+        assert!(transcript.contains("RCPT TO:<jeff@example.com>"));
+        assert!(transcript.contains("Subject: Page 4821 for +12183957949"));
+        assert!(transcript.contains("New page for +12183957949"));
+
+        let (port, _stand_in) = smtp_stand_in("550 mailbox unavailable");
+        let channel = EmailChannel::new(smtp_config(port));
+        let result = runtime.block_on(channel.deliver("jeff@example.com", &test_page()));
+        assert_eq!(
+            result,
+            Err(NotifyError::Rejected {
+                code: 550,
+                message: "mailbox unavailable".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn test_webhook_channel() {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let channel = WebhookChannel::new();
+        assert_eq!(channel.kind(), ChannelKind::Webhook);
+
+        let (port, stand_in) = webhook_stand_in("200 OK");
+        let url = format!("http://127.0.0.1:{}/hooks/pager", port);
+        let result = runtime.block_on(channel.deliver(&url, &test_page()));
+        assert_eq!(result, Ok(()));
+        let body: serde_json::Value = serde_json::from_str(&stand_in.join().unwrap()).unwrap();
+        assert_eq!(body["text"], "New page for +12183957949");
+        assert_eq!(body["call_id"], "00000000-0000-0000-0000-000000000000");
+
+        let (port, _stand_in) = webhook_stand_in("404 Not Found");
+        let url = format!("http://127.0.0.1:{}/hooks/pager", port);
+        let result = runtime.block_on(channel.deliver(&url, &test_page()));
+        assert_eq!(
+            result,
+            Err(NotifyError::Rejected {
+                code: 404,
+                message: "Not Found".to_owned()
+            })
+        );
+    }
+}
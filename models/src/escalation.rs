@@ -0,0 +1,514 @@
+use crate::forward::Forwarding;
+use crate::schedule::Schedule;
+use crate::users::User;
+use chrono::{offset::FixedOffset, DateTime, Duration};
+use dynomite::{
+    dynamodb::{
+        AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput,
+    },
+    error::AttributeError,
+    Attribute, Attributes, FromAttributes, Item,
+};
+use rusoto_core::{Region, RusotoError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Who gets paged when an escalation level is reached.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EscalationTarget {
+    /// Whoever is on call in the schedule stored under this key
+    Schedule(String),
+    /// A fixed list of users
+    Users(Vec<User>),
+    /// Whoever the other group would page first
+    Group(String),
+}
+
+impl Attribute for EscalationTarget {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        match self {
+            EscalationTarget::Schedule(key) => {
+                map.insert("type".to_string(), "Schedule".to_string().into_attr());
+                map.insert("value".to_string(), key.into_attr());
+            }
+            EscalationTarget::Users(users) => {
+                map.insert("type".to_string(), "Users".to_string().into_attr());
+                map.insert("value".to_string(), users.into_attr());
+            }
+            EscalationTarget::Group(group_id) => {
+                map.insert("type".to_string(), "Group".to_string().into_attr());
+                map.insert("value".to_string(), group_id.into_attr());
+            }
+        }
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        let mut m = value.m.ok_or(AttributeError::InvalidType)?;
+        let value = m.remove("value").ok_or(AttributeError::MissingField {
+            name: "value".to_string(),
+        })?;
+        match String::from_attr(m.remove("type").ok_or(AttributeError::MissingField {
+            name: "type".to_string(),
+        })?)?
+        .as_str()
+        {
+            "Schedule" => Ok(EscalationTarget::Schedule(String::from_attr(value)?)),
+            "Users" => Ok(EscalationTarget::Users(Vec::<User>::from_attr(value)?)),
+            "Group" => Ok(EscalationTarget::Group(String::from_attr(value)?)),
+            _ => Err(AttributeError::InvalidFormat),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EscalationLevel {
+    targets: Vec<EscalationTarget>,
+    /// Seconds to wait for an acknowledgement before moving on
+    timeout: i64,
+    /// How many more times this level is paged before escalating
+    repeat: u32,
+}
+
+impl EscalationLevel {
+    pub fn new_escalation_level(
+        targets: Vec<EscalationTarget>,
+        timeout: i64,
+        repeat: u32,
+    ) -> EscalationLevel {
+        EscalationLevel {
+            targets,
+            timeout,
+            repeat,
+        }
+    }
+
+    pub fn timeout(&self) -> i64 {
+        self.timeout
+    }
+
+    /// Resolves the level's targets into the users on call at `date_time`.
+    /// Group targets only follow the other group's first level one step deep,
+    /// so two groups pointing at each other cannot loop.
+    pub async fn resolve_users(
+        &self,
+        group_table: String,
+        escalation_table: String,
+        region: Region,
+        date_time: DateTime<FixedOffset>,
+    ) -> Vec<User> {
+        let mut users: Vec<User> = Vec::new();
+        for target in &self.targets {
+            let found = match target {
+                EscalationTarget::Schedule(key) => {
+                    schedule_providers(group_table.clone(), region.clone(), key.clone(), date_time)
+                        .await
+                }
+                EscalationTarget::Users(list) => list.clone(),
+                EscalationTarget::Group(group_id) => {
+                    let policy = EscalationPolicy::get_escalation_policy(
+                        escalation_table.clone(),
+                        region.clone(),
+                        group_id.clone(),
+                    )
+                    .await
+                    .unwrap_or_else(|| EscalationPolicy::default_policy(group_id.clone()));
+                    let mut group_users = Vec::new();
+                    for other in policy.levels.first().map_or(&[][..], |l| &l.targets[..]) {
+                        match other {
+                            EscalationTarget::Schedule(key) => group_users.append(
+                                &mut schedule_providers(
+                                    group_table.clone(),
+                                    region.clone(),
+                                    key.clone(),
+                                    date_time,
+                                )
+                                .await,
+                            ),
+                            EscalationTarget::Users(list) => group_users.append(&mut list.clone()),
+                            EscalationTarget::Group(_) => (),
+                        }
+                    }
+                    group_users
+                }
+            };
+            for user in found {
+                if !users.contains(&user) {
+                    users.push(user);
+                }
+            }
+        }
+        users
+    }
+}
+
+async fn schedule_providers(
+    group_table: String,
+    region: Region,
+    key: String,
+    date_time: DateTime<FixedOffset>,
+) -> Vec<User> {
+    Schedule::get_schedule(group_table, region, key, "group_id".to_string())
+        .await
+        .and_then(|schedule| schedule.get_providers(date_time))
+        .unwrap_or_else(Vec::new)
+}
+
+impl Attribute for EscalationLevel {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("targets".to_string(), self.targets.into_attr());
+        map.insert("timeout".to_string(), self.timeout.into_attr());
+        map.insert("repeat".to_string(), self.repeat.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(EscalationLevel {
+                    targets: Vec::<EscalationTarget>::from_attr(field("targets")?)?,
+                    timeout: i64::from_attr(field("timeout")?)?,
+                    repeat: u32::from_attr(field("repeat")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// What happens to a call once its policy's attempts are used up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TerminalAction {
+    /// Page everyone in the group's schedule at once
+    PageGroup,
+    /// Page a fixed list of managers
+    NotifyManagers(Vec<User>),
+    /// Stop paging and mark the call expired
+    Expire,
+}
+
+impl Attribute for TerminalAction {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        match self {
+            TerminalAction::PageGroup => {
+                map.insert("type".to_string(), "PageGroup".to_string().into_attr());
+            }
+            TerminalAction::NotifyManagers(users) => {
+                map.insert("type".to_string(), "NotifyManagers".to_string().into_attr());
+                map.insert("value".to_string(), users.into_attr());
+            }
+            TerminalAction::Expire => {
+                map.insert("type".to_string(), "Expire".to_string().into_attr());
+            }
+        }
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        let mut m = value.m.ok_or(AttributeError::InvalidType)?;
+        match String::from_attr(m.remove("type").ok_or(AttributeError::MissingField {
+            name: "type".to_string(),
+        })?)?
+        .as_str()
+        {
+            "PageGroup" => Ok(TerminalAction::PageGroup),
+            "NotifyManagers" => Ok(TerminalAction::NotifyManagers(Vec::<User>::from_attr(
+                m.remove("value").ok_or(AttributeError::MissingField {
+                    name: "value".to_string(),
+                })?,
+            )?)),
+            "Expire" => Ok(TerminalAction::Expire),
+            _ => Err(AttributeError::InvalidFormat),
+        }
+    }
+}
+
+/// Pages a call may get before its terminal action runs, for policies that
+/// don't set their own
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+pub const DEFAULT_DEDUP_WINDOW: i64 = 600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Item)]
+pub struct EscalationPolicy {
+    #[dynomite(partition_key)]
+    group_id: String,
+    levels: Vec<EscalationLevel>,
+    /// Pages sent for a call, across all levels, before `terminal` runs
+    max_attempts: u32,
+    terminal: TerminalAction,
+    /// Seconds after a call during which another call from the same number
+    /// joins its incident instead of starting a new one. 0 disables it.
+    dedup_window: i64,
+    /// Set on lines where callers are put through to whoever is on call
+    /// before being asked for a message
+    forwarding: Option<Forwarding>,
+}
+
+impl EscalationPolicy {
+    pub fn new_escalation_policy(
+        group_id: String,
+        levels: Vec<EscalationLevel>,
+        max_attempts: u32,
+        terminal: TerminalAction,
+    ) -> EscalationPolicy {
+        EscalationPolicy {
+            group_id,
+            levels,
+            max_attempts,
+            terminal,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            forwarding: None,
+        }
+    }
+
+    /// Policy used for groups that have not configured one: page whoever is on
+    /// call in the group's schedule every 50 seconds, then the whole group.
+    pub fn default_policy(group_id: String) -> EscalationPolicy {
+        EscalationPolicy {
+            levels: vec![EscalationLevel::new_escalation_level(
+                vec![EscalationTarget::Schedule(group_id.clone())],
+                50,
+                0,
+            )],
+            group_id,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            terminal: TerminalAction::PageGroup,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            forwarding: None,
+        }
+    }
+
+    pub fn group_id(&self) -> &str {
+        &self.group_id
+    }
+
+    pub fn terminal(&self) -> &TerminalAction {
+        &self.terminal
+    }
+
+    pub fn dedup_window(&self) -> Duration {
+        Duration::seconds(self.dedup_window)
+    }
+
+    pub fn set_dedup_window(&mut self, seconds: i64) {
+        self.dedup_window = seconds;
+    }
+
+    pub fn forwarding(&self) -> Option<&Forwarding> {
+        self.forwarding.as_ref()
+    }
+
+    pub fn set_forwarding(&mut self, forwarding: Option<Forwarding>) {
+        self.forwarding = forwarding;
+    }
+
+    /// Whether a call paged `attempts` times has used up the policy
+    pub fn exhausted(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+
+    pub fn level(&self, level: u32) -> Option<&EscalationLevel> {
+        self.levels.get(level as usize)
+    }
+
+    pub fn last_level(&self) -> Option<&EscalationLevel> {
+        self.levels.last()
+    }
+
+    /// Returns the `(level, repeat)` to page after `(level, repeat)` times out,
+    /// or `None` once every level has been paged as often as it allows.
+    pub fn next_step(&self, level: u32, repeat: u32) -> Option<(u32, u32)> {
+        let current = self.level(level)?;
+        if repeat < current.repeat {
+            Some((level, repeat + 1))
+        } else if self.level(level + 1).is_some() {
+            Some((level + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// The level after `level`, skipping any repeats left on it
+    pub fn next_level(&self, level: u32) -> Option<u32> {
+        self.level(level + 1).map(|_| level + 1)
+    }
+
+    /// Stores the policy, refusing one without levels
+    pub async fn write_escalation_policy(
+        &self,
+        table_name: String,
+        region: Region,
+    ) -> Result<(), RusotoError<PutItemError>> {
+        if self.levels.is_empty() {
+            return Err(RusotoError::Validation(
+                "An escalation policy needs at least one level".to_string(),
+            ));
+        }
+        let client = DynamoDbClient::new(region);
+        client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(),
+                ..PutItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_escalation_policy(
+        table_name: String,
+        region: Region,
+        key: String,
+    ) -> Option<EscalationPolicy> {
+        let client = DynamoDbClient::new(region);
+        let mut key_map = HashMap::new();
+        key_map.insert("group_id".to_string(), key.into_attr());
+        client
+            .get_item(GetItemInput {
+                table_name,
+                key: key_map,
+                ..GetItemInput::default()
+            })
+            .await
+            .ok()
+            .and_then(|output| output.item)
+            .and_then(|attrs| EscalationPolicy::from_stored(attrs).ok())
+    }
+
+    /// Reads a stored policy, including ones saved before later settings
+    /// existed. A policy without levels can't page anyone and is refused, so
+    /// the group falls back to the default policy.
+    pub fn from_stored(mut attrs: Attributes) -> Result<EscalationPolicy, AttributeError> {
+        // Policies saved before attempts were bounded
+        if !attrs.contains_key("max_attempts") {
+            attrs.insert("max_attempts".to_string(), DEFAULT_MAX_ATTEMPTS.into_attr());
+        }
+        if !attrs.contains_key("terminal") {
+            attrs.insert(
+                "terminal".to_string(),
+                TerminalAction::PageGroup.into_attr(),
+            );
+        }
+        if !attrs.contains_key("dedup_window") {
+            attrs.insert("dedup_window".to_string(), DEFAULT_DEDUP_WINDOW.into_attr());
+        }
+        if !attrs.contains_key("forwarding") {
+            attrs.insert(
+                "forwarding".to_string(),
+                Option::<Forwarding>::None.into_attr(),
+            );
+        }
+        let policy = EscalationPolicy::from_attrs(attrs)?;
+        if policy.levels.is_empty() {
+            return Err(AttributeError::InvalidFormat);
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward::DialStrategy;
+
+    fn test_policy() -> EscalationPolicy {
+        let jeff = User::new_user(
+            "2".to_owned(),
+            "+12183957949".to_owned(),
+            "Jeff Winger".to_owned(),
+            "+19147251309".to_owned(),
+        );
+        EscalationPolicy::new_escalation_policy(
+            "+12183957949".to_owned(),
+            vec![
+                EscalationLevel::new_escalation_level(
+                    vec![EscalationTarget::Schedule("+12183957949".to_owned())],
+                    60,
+                    1,
+                ),
+                EscalationLevel::new_escalation_level(
+                    vec![
+                        EscalationTarget::Users(vec![jeff]),
+                        EscalationTarget::Group("+13473513315".to_owned()),
+                    ],
+                    300,
+                    0,
+                ),
+            ],
+            3,
+            TerminalAction::NotifyManagers(vec![User::new_user(
+                "5".to_owned(),
+                "+12183957949".to_owned(),
+                "Test Manager".to_owned(),
+                "+19143745558".to_owned(),
+            )]),
+        )
+    }
+
+    #[test]
+    fn test_next_step() {
+        let policy = test_policy();
+        assert_eq!(policy.next_step(0, 0), Some((0, 1)));
+        assert_eq!(policy.next_step(0, 1), Some((1, 0)));
+        assert_eq!(policy.next_step(1, 0), None);
+        assert_eq!(policy.next_step(2, 0), None);
+        assert_eq!(policy.level(1).unwrap().timeout(), 300);
+        assert_eq!(policy.next_level(0), Some(1));
+        assert_eq!(policy.next_level(1), None);
+        assert_eq!(
+            policy.level(5).or_else(|| policy.last_level()),
+            policy.level(1)
+        );
+        assert!(!policy.exhausted(2));
+        assert!(policy.exhausted(3));
+    }
+
+    #[test]
+    fn test_policy_attributes() {
+        let mut policy = test_policy();
+        policy.set_dedup_window(300);
+        policy.set_forwarding(Some(Forwarding::new_forwarding(
+            DialStrategy::Sequential,
+            20,
+        )));
+        let attrs: dynomite::Attributes = policy.clone().into();
+        let read = EscalationPolicy::from_stored(attrs.clone()).unwrap();
+        assert_eq!(read.group_id, policy.group_id);
+        assert_eq!(read.levels, policy.levels);
+        assert_eq!(read.max_attempts, 3);
+        assert_eq!(read.terminal, policy.terminal);
+        assert_eq!(read.dedup_window(), Duration::minutes(5));
+        assert_eq!(read.forwarding(), policy.forwarding());
+
+        let mut empty = attrs;
+        empty.insert(
+            "levels".to_string(),
+            Vec::<EscalationLevel>::new().into_attr(),
+        );
+        assert!(EscalationPolicy::from_stored(empty).is_err());
+
+        let mut level = policy.levels[0].clone().into_attr();
+        level.m.as_mut().unwrap().remove("repeat");
+        assert!(matches!(
+            EscalationLevel::from_attr(level),
+            Err(AttributeError::MissingField { name }) if name == "repeat"
+        ));
+    }
+}
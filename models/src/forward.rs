@@ -0,0 +1,146 @@
+use crate::ivr::call_action;
+use crate::twiml::{Dial, Method, TwimlResponse};
+use dynomite::{dynamodb::AttributeValue, error::AttributeError, Attribute};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Attribute)]
+pub enum DialStrategy {
+    /// Ring providers one after another, in schedule order
+    Sequential,
+    /// Ring every provider at once and connect whoever answers first
+    Simultaneous,
+}
+
+/// Connects callers straight to whoever is on call instead of taking a
+/// message, for lines where someone should pick up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Forwarding {
+    pub strategy: DialStrategy,
+    /// Seconds to ring each attempt before giving up on it
+    pub timeout: u32,
+}
+
+impl Attribute for Forwarding {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("strategy".to_string(), self.strategy.into_attr());
+        map.insert("timeout".to_string(), self.timeout.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(Forwarding {
+                    strategy: DialStrategy::from_attr(field("strategy")?)?,
+                    timeout: u32::from_attr(field("timeout")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+impl Forwarding {
+    pub fn new_forwarding(strategy: DialStrategy, timeout: u32) -> Forwarding {
+        Forwarding { strategy, timeout }
+    }
+
+    /// TwiML ringing `numbers` from index `next` on. When the dial ends
+    /// Twilio posts back to the call with `dial` set to the index to try
+    /// after it, so the caller can be passed on if nobody answered. `None`
+    /// once every number has been tried.
+    pub fn dial_twiml(
+        &self,
+        numbers: &[String],
+        next: usize,
+        call_id: &Uuid,
+        option: Option<&str>,
+    ) -> Option<String> {
+        let (ringing, after) = match self.strategy {
+            DialStrategy::Sequential => (numbers.get(next..=next)?, next + 1),
+            DialStrategy::Simultaneous if next == 0 && !numbers.is_empty() => {
+                (numbers, numbers.len())
+            }
+            DialStrategy::Simultaneous => return None,
+        };
+        let mut twiml = TwimlResponse::new_response();
+        if next == 0 {
+            twiml = twiml.say("Connecting you to the on-call engineer.");
+        }
+        let dial = ringing.iter().fold(
+            Dial::new_dial().timeout(self.timeout).action(
+                &call_action(call_id, option, &[("dial", after.to_string())]),
+                Method::Post,
+            ),
+            |dial, number| dial.number(number),
+        );
+        Some(twiml.dial(dial).to_string())
+    }
+}
+
+/// Whether a `DialCallStatus` means someone picked up, in which case the
+/// caller has already been helped and should not be asked for a message
+pub fn dial_answered(status: &str) -> bool {
+    status == "completed"
+}
+
+/// Ends a call whose dial was answered once the conversation is over
+pub fn hangup_twiml() -> String {
+    TwimlResponse::new_response().hangup().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dial_twiml() {
+        let numbers = vec!["+19149543303".to_owned(), "+19147251309".to_owned()];
+
+        let sequential = Forwarding::new_forwarding(DialStrategy::Sequential, 20);
+        let twiml = sequential
+            .dial_twiml(&numbers, 0, &Uuid::nil(), None)
+            .unwrap();
+        assert!(twiml.contains("Connecting you"));
+        assert!(twiml.contains(
+            r#"<Dial timeout="20" action="?call_id=00000000-0000-0000-0000-000000000000&amp;dial=1" method="POST"><Number>+19149543303</Number></Dial>"#
+        ));
+        let twiml = sequential
+            .dial_twiml(&numbers, 1, &Uuid::nil(), Some("2"))
+            .unwrap();
+        assert!(!twiml.contains("Connecting you"));
+        assert!(twiml.contains("&amp;dial=2&amp;option=2"));
+        assert!(twiml.contains("<Number>+19147251309</Number>"));
+        assert_eq!(sequential.dial_twiml(&numbers, 2, &Uuid::nil(), None), None);
+
+        let simultaneous = Forwarding::new_forwarding(DialStrategy::Simultaneous, 30);
+        let twiml = simultaneous
+            .dial_twiml(&numbers, 0, &Uuid::nil(), None)
+            .unwrap();
+        assert!(twiml.contains("<Number>+19149543303</Number><Number>+19147251309</Number></Dial>"));
+        assert!(twiml.contains("&amp;dial=2"));
+        assert_eq!(
+            simultaneous.dial_twiml(&numbers, 2, &Uuid::nil(), None),
+            None
+        );
+        assert_eq!(simultaneous.dial_twiml(&[], 0, &Uuid::nil(), None), None);
+
+        assert!(dial_answered("completed"));
+        assert!(!dial_answered("no-answer"));
+        assert!(!dial_answered("busy"));
+
+        let forwarding = Forwarding::from_attr(simultaneous.clone().into_attr()).unwrap();
+        assert_eq!(forwarding, simultaneous);
+    }
+}
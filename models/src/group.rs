@@ -0,0 +1,508 @@
+use crate::users::User;
+use crate::validate::{check_not_blank, check_phone_number, ValidationError};
+use chrono::offset::{FixedOffset, Offset, Utc};
+use dynomite::{
+    dynamodb::{
+        AttributeValue, DeleteItemError, DeleteItemInput, DynamoDb, DynamoDbClient, GetItemInput,
+        PutItemError, PutItemInput, ScanError, ScanInput,
+    },
+    Attribute, AttributeError, Attributes, FromAttributes, Item,
+};
+use log::warn;
+use rusoto_core::RusotoError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A team that is paged together. Its schedule, escalation policy and phone
+/// menu are all stored under its `group_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Item)]
+pub struct Group {
+    #[dynomite(partition_key)]
+    pub group_id: String,
+    pub name: String,
+    /// Seconds east of UTC the group's schedule is kept in
+    pub utc_offset: i32,
+    /// Twilio numbers that ring through to the group
+    pub numbers: Vec<String>,
+    pub members: Vec<User>,
+    /// Said to callers before they leave a message, when there is no menu
+    pub greeting: Option<String>,
+    /// Bumped on every write. A write only succeeds if the stored group
+    /// still has the version it was read with.
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Index from an inbound number to the group it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Item)]
+pub struct GroupNumber {
+    #[dynomite(partition_key)]
+    pub number: String,
+    pub group_id: String,
+}
+
+#[derive(Debug)]
+pub enum GroupWriteError {
+    /// Includes `PutItemError::ConditionalCheckFailed` when creating a group
+    /// whose id is taken or writing one changed since it was read
+    Group(RusotoError<PutItemError>),
+    /// The number already rings through to another group
+    NumberClaimed(String),
+    Numbers(RusotoError<PutItemError>),
+}
+
+impl Group {
+    pub fn new_group(
+        group_id: String,
+        name: String,
+        utc_offset: i32,
+        numbers: Vec<String>,
+    ) -> Group {
+        Group {
+            group_id,
+            name,
+            utc_offset,
+            numbers,
+            members: Vec::new(),
+            greeting: None,
+            version: 0,
+        }
+    }
+
+    /// The group's time zone, UTC if the stored offset is out of range
+    pub fn offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.utc_offset).unwrap_or_else(|| Utc.fix())
+    }
+
+    /// The member with `number` as one of their phones, if any
+    pub fn member(&self, number: &str) -> Option<&User> {
+        self.members.iter().find(|user| user.has_phone(number))
+    }
+
+    /// Checks the group and each of its members before they are stored
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let id_chars = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if self.group_id.is_empty()
+            || self.group_id.len() > 64
+            || !self.group_id.chars().all(id_chars)
+        {
+            return Err(ValidationError::new(
+                "group_id",
+                "must be 1 to 64 lowercase letters, digits or dashes",
+            ));
+        }
+        check_not_blank("name", &self.name)?;
+        if FixedOffset::east_opt(self.utc_offset).is_none() {
+            return Err(ValidationError::new(
+                "utc_offset",
+                "must be less than a day either way, in seconds",
+            ));
+        }
+        if self.numbers.is_empty() {
+            return Err(ValidationError::new("numbers", "must not be empty"));
+        }
+        for (i, number) in self.numbers.iter().enumerate() {
+            check_phone_number(&format!("numbers[{}]", i), number)?;
+            if self.numbers[..i].contains(number) {
+                return Err(ValidationError::new(
+                    &format!("numbers[{}]", i),
+                    "is listed twice",
+                ));
+            }
+        }
+        if let Some(greeting) = &self.greeting {
+            check_not_blank("greeting", greeting)?;
+        }
+        for (i, user) in self.members.iter().enumerate() {
+            user.validate().map_err(|e| {
+                ValidationError::new(&format!("members[{}].{}", i, e.field), &e.reason)
+            })?;
+            if user.group_id() != self.group_id {
+                return Err(ValidationError::new(
+                    &format!("members[{}].group_id", i),
+                    "must be the group's id",
+                ));
+            }
+            if self.members[..i]
+                .iter()
+                .any(|other| other.uuid() == user.uuid())
+            {
+                return Err(ValidationError::new(
+                    &format!("members[{}].uuid", i),
+                    "is used by another member",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Claims the group's numbers and creates it, failing with
+    /// `PutItemError::ConditionalCheckFailed` if its id is taken
+    pub async fn create_group(
+        &mut self,
+        group_table: String,
+        number_table: String,
+        client: &DynamoDbClient,
+    ) -> Result<(), GroupWriteError> {
+        self.put_group(
+            group_table,
+            number_table,
+            client,
+            "attribute_not_exists(group_id)",
+            HashMap::new(),
+        )
+        .await
+    }
+
+    /// Claims the group's numbers, writes it and bumps its version, failing
+    /// with `PutItemError::ConditionalCheckFailed` if someone else wrote it
+    /// since it was read. Numbers in `given_up` it no longer has are freed.
+    pub async fn write_group(
+        &mut self,
+        group_table: String,
+        number_table: String,
+        client: &DynamoDbClient,
+        given_up: &[String],
+    ) -> Result<(), GroupWriteError> {
+        let mut values = HashMap::new();
+        // Version 0 covers groups stored before groups were versioned
+        let condition = if self.version == 0 {
+            "attribute_not_exists(version)"
+        } else {
+            values.insert(":version".to_string(), self.version.into_attr());
+            "version = :version"
+        };
+        self.put_group(group_table, number_table.clone(), client, condition, values)
+            .await?;
+        let given_up: Vec<String> = given_up
+            .iter()
+            .filter(|number| !self.numbers.contains(number))
+            .cloned()
+            .collect();
+        // Lookups already ignore numbers the group doesn't list, so one left
+        // behind only keeps other groups from claiming it
+        if let Err(e) = self.release_numbers(client, &number_table, &given_up).await {
+            warn!("Freeing numbers of {} failed: {:?}", self.group_id, e);
+        }
+        Ok(())
+    }
+
+    async fn put_group(
+        &mut self,
+        group_table: String,
+        number_table: String,
+        client: &DynamoDbClient,
+        condition: &str,
+        values: HashMap<String, AttributeValue>,
+    ) -> Result<(), GroupWriteError> {
+        let mut claimed = Vec::new();
+        let mut written = self
+            .claim_numbers(client, &number_table, &mut claimed)
+            .await;
+        if written.is_ok() {
+            let read_version = self.version;
+            self.version += 1;
+            written = client
+                .put_item(PutItemInput {
+                    table_name: group_table,
+                    item: self.clone().into(),
+                    condition_expression: Some(condition.to_string()),
+                    expression_attribute_values: if values.is_empty() {
+                        None
+                    } else {
+                        Some(values)
+                    },
+                    ..PutItemInput::default()
+                })
+                .await
+                .map(|_| ())
+                .map_err(GroupWriteError::Group);
+            if written.is_err() {
+                self.version = read_version;
+            }
+        }
+        if written.is_err() {
+            if let Err(e) = self.release_numbers(client, &number_table, &claimed).await {
+                warn!("Freeing numbers of {} failed: {:?}", self.group_id, e);
+            }
+        }
+        written
+    }
+
+    /// Indexes each of the group's numbers to it unless another group has
+    /// it, noting in `claimed` the ones that weren't already the group's
+    async fn claim_numbers(
+        &self,
+        client: &DynamoDbClient,
+        number_table: &str,
+        claimed: &mut Vec<String>,
+    ) -> Result<(), GroupWriteError> {
+        // NUMBER is reserved in expressions
+        let mut names = HashMap::new();
+        names.insert("#number".to_string(), "number".to_string());
+        let mut values = HashMap::new();
+        values.insert(":group_id".to_string(), self.group_id.clone().into_attr());
+        for number in &self.numbers {
+            let indexed = client
+                .put_item(PutItemInput {
+                    table_name: number_table.to_string(),
+                    item: GroupNumber {
+                        number: number.clone(),
+                        group_id: self.group_id.clone(),
+                    }
+                    .into(),
+                    condition_expression: Some(
+                        "attribute_not_exists(#number) OR group_id = :group_id".to_string(),
+                    ),
+                    expression_attribute_names: Some(names.clone()),
+                    expression_attribute_values: Some(values.clone()),
+                    return_values: Some("ALL_OLD".to_string()),
+                    ..PutItemInput::default()
+                })
+                .await;
+            match indexed {
+                Ok(output) => {
+                    if output.attributes.map_or(true, |old| old.is_empty()) {
+                        claimed.push(number.clone());
+                    }
+                }
+                Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => {
+                    return Err(GroupWriteError::NumberClaimed(number.clone()))
+                }
+                Err(e) => return Err(GroupWriteError::Numbers(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `numbers` from the index, leaving alone any that another
+    /// group has since claimed
+    async fn release_numbers(
+        &self,
+        client: &DynamoDbClient,
+        number_table: &str,
+        numbers: &[String],
+    ) -> Result<(), RusotoError<DeleteItemError>> {
+        let mut values = HashMap::new();
+        values.insert(":group_id".to_string(), self.group_id.clone().into_attr());
+        for number in numbers {
+            let mut key = HashMap::new();
+            key.insert("number".to_string(), number.clone().into_attr());
+            let deleted = client
+                .delete_item(DeleteItemInput {
+                    table_name: number_table.to_string(),
+                    key,
+                    condition_expression: Some("group_id = :group_id".to_string()),
+                    expression_attribute_values: Some(values.clone()),
+                    ..DeleteItemInput::default()
+                })
+                .await;
+            match deleted {
+                Ok(_) | Err(RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a stored group, including ones written before groups had a
+    /// version
+    pub fn from_stored(mut attrs: Attributes) -> Result<Group, AttributeError> {
+        if !attrs.contains_key("version") {
+            attrs.insert("version".to_string(), 0u64.into_attr());
+        }
+        Group::from_attrs(attrs)
+    }
+
+    pub async fn get_group(
+        table_name: String,
+        client: &DynamoDbClient,
+        key: String,
+    ) -> Option<Group> {
+        let mut key_map = HashMap::new();
+        key_map.insert("group_id".to_string(), key.into_attr());
+        client
+            .get_item(GetItemInput {
+                table_name,
+                key: key_map,
+                ..GetItemInput::default()
+            })
+            .await
+            .ok()
+            .and_then(|output| output.item)
+            .and_then(|attrs| Group::from_stored(attrs).ok())
+    }
+
+    /// Every group, paging through the whole table
+    pub async fn get_groups(
+        table_name: String,
+        client: &DynamoDbClient,
+    ) -> Result<Vec<Group>, RusotoError<ScanError>> {
+        let mut groups = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = client
+                .scan(ScanInput {
+                    table_name: table_name.clone(),
+                    exclusive_start_key: start_key,
+                    ..ScanInput::default()
+                })
+                .await?;
+            groups.extend(
+                output
+                    .items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|attrs| Group::from_stored(attrs).ok()),
+            );
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Ok(groups);
+            }
+        }
+    }
+
+    /// Deletes the group and frees its numbers. Its schedule, escalation
+    /// policy and menu are left in place.
+    pub async fn delete_group(
+        &self,
+        group_table: String,
+        number_table: String,
+        client: &DynamoDbClient,
+    ) -> Result<(), RusotoError<DeleteItemError>> {
+        self.release_numbers(client, &number_table, &self.numbers)
+            .await?;
+        let mut key = HashMap::new();
+        key.insert("group_id".to_string(), self.group_id.clone().into_attr());
+        client
+            .delete_item(DeleteItemInput {
+                table_name: group_table,
+                key,
+                ..DeleteItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The group an inbound call or SMS to `number` is for
+    pub async fn get_group_by_number(
+        group_table: String,
+        number_table: String,
+        client: &DynamoDbClient,
+        number: String,
+    ) -> Option<Group> {
+        let mut key_map = HashMap::new();
+        key_map.insert("number".to_string(), number.clone().into_attr());
+        let indexed = client
+            .get_item(GetItemInput {
+                table_name: number_table,
+                key: key_map,
+                ..GetItemInput::default()
+            })
+            .await
+            .ok()
+            .and_then(|output| output.item)
+            .and_then(|attrs| GroupNumber::from_attrs(attrs).ok())?;
+        // The index can lag behind a group giving a number up
+        Group::get_group(group_table, client, indexed.group_id)
+            .await
+            .filter(|group| group.numbers.contains(&number))
+    }
+}
+
+/// The group id calls and texts to `number` are filed under: the group's
+/// own id, or the number itself for groups set up before groups had ids
+pub fn group_key(group: Option<&Group>, number: &str) -> String {
+    match group {
+        Some(group) => group.group_id.clone(),
+        None => number.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_group() -> Group {
+        let mut group = Group::new_group(
+            "acme-support".to_owned(),
+            "Acme Support".to_owned(),
+            -4 * 3600,
+            vec!["+12183957949".to_owned(), "+12183950000".to_owned()],
+        );
+        group.members.push(User::new_user(
+            "1".to_owned(),
+            "acme-support".to_owned(),
+            "Tobias Funke".to_owned(),
+            "+19149543303".to_owned(),
+        ));
+        group.greeting = Some("Thanks for calling Acme.".to_owned());
+        group
+    }
+
+    #[test]
+    fn test_offset_and_members() {
+        let group = test_group();
+        assert_eq!(group.offset(), FixedOffset::west_opt(4 * 3600).unwrap());
+        assert_eq!(
+            group.member("+19149543303").map(|user| user.name()),
+            Some("Tobias Funke")
+        );
+        assert_eq!(group.member("+13473513315"), None);
+        assert_eq!(group_key(Some(&group), "+12183950000"), "acme-support");
+        assert_eq!(group_key(None, "+12183950000"), "+12183950000");
+
+        let mut far = group.clone();
+        far.utc_offset = 48 * 3600;
+        assert_eq!(far.offset(), Utc.fix());
+
+        assert_eq!(group.validate(), Ok(()));
+
+        let attrs: dynomite::Attributes = group.clone().into();
+        assert_eq!(Group::from_stored(attrs.clone()).unwrap(), group);
+
+        // Groups stored before groups were versioned
+        let mut old = attrs;
+        old.remove("version");
+        assert_eq!(Group::from_stored(old).unwrap(), group);
+    }
+    #[test]
+    fn test_validate() {
+        let field = |group: &Group| group.validate().unwrap_err().field;
+        let mut group = test_group();
+        group.group_id = "Acme Support".to_owned();
+        assert_eq!(field(&group), "group_id");
+
+        let mut group = test_group();
+        group.utc_offset = 24 * 3600;
+        assert_eq!(field(&group), "utc_offset");
+
+        let mut group = test_group();
+        group.numbers.push("+12183957949".to_owned());
+        assert_eq!(field(&group), "numbers[2]");
+        group.numbers.clear();
+        assert_eq!(field(&group), "numbers");
+
+        let mut group = test_group();
+        group.greeting = Some(" ".to_owned());
+        assert_eq!(field(&group), "greeting");
+
+        let mut group = test_group();
+        let twin = group.members[0].clone();
+        group.members.push(twin);
+        assert_eq!(field(&group), "members[1].uuid");
+
+        let mut group = test_group();
+        group.members.push(User::new_user(
+            "2".to_owned(),
+            "other-group".to_owned(),
+            "Jeff Winger".to_owned(),
+            "+19147251309".to_owned(),
+        ));
+        assert_eq!(field(&group), "members[1].group_id");
+
+        let mut group = test_group();
+        group.members[0].number = "555-1234".to_owned();
+        assert_eq!(field(&group), "members[0].number");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::twiml::{Gather, Method, Record, TwimlResponse};
+use crate::webhook::callback_url;
+use dynomite::{
+    dynamodb::{
+        AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput,
+    },
+    error::AttributeError,
+    Attribute, FromAttributes, Item,
+};
+use rusoto_core::{Region, RusotoError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Attribute)]
+pub enum Severity {
+    Low,
+    Normal,
+    High,
+    Critical,
+}
+
+/// One choice in a group's phone menu, e.g. "press 2 for outages"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IvrOption {
+    pub digit: String,
+    /// Read out in the menu and shown in pages, e.g. "outages"
+    pub label: String,
+    /// Key of the schedule and escalation policy calls for this option are
+    /// routed to, in place of the group's own
+    pub route: String,
+    pub severity: Severity,
+}
+
+impl Attribute for IvrOption {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("digit".to_string(), self.digit.into_attr());
+        map.insert("label".to_string(), self.label.into_attr());
+        map.insert("route".to_string(), self.route.into_attr());
+        map.insert("severity".to_string(), self.severity.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(IvrOption {
+                    digit: String::from_attr(field("digit")?)?,
+                    label: String::from_attr(field("label")?)?,
+                    route: String::from_attr(field("route")?)?,
+                    severity: Severity::from_attr(field("severity")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// Menu played to callers of a group number before they leave a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Item)]
+pub struct IvrMenu {
+    #[dynomite(partition_key)]
+    pub group_id: String,
+    /// Said before the options, e.g. "Thanks for calling Acme support."
+    pub greeting: String,
+    pub options: Vec<IvrOption>,
+}
+
+impl IvrMenu {
+    pub fn new_ivr_menu(group_id: String, greeting: String, options: Vec<IvrOption>) -> IvrMenu {
+        IvrMenu {
+            group_id,
+            greeting,
+            options,
+        }
+    }
+
+    pub fn option(&self, digit: &str) -> Option<&IvrOption> {
+        self.options.iter().find(|option| option.digit == digit)
+    }
+
+    /// Asks the caller to pick an option. The key pressed is posted to
+    /// `action`; callers who press nothing go straight to leaving a message.
+    pub fn menu_twiml(&self, action: &str, call_id: &Uuid, transcription_url: &str) -> String {
+        let choices = self
+            .options
+            .iter()
+            .map(|option| format!("For {}, press {}.", option.label, option.digit))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let menu = TwimlResponse::new_response().gather(
+            Gather::new_gather()
+                .num_digits(1)
+                .action(action, Method::Post)
+                .say(&format!("{} {}", self.greeting, choices)),
+        );
+        take_message(menu, call_id, None, transcription_url).to_string()
+    }
+
+    pub async fn write_ivr_menu(
+        &self,
+        table_name: String,
+        region: Region,
+    ) -> Result<(), RusotoError<PutItemError>> {
+        let client = DynamoDbClient::new(region);
+        client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(),
+                ..PutItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_ivr_menu(table_name: String, region: Region, key: String) -> Option<IvrMenu> {
+        let client = DynamoDbClient::new(region);
+        let mut key_map = HashMap::new();
+        key_map.insert("group_id".to_string(), key.into_attr());
+        client
+            .get_item(GetItemInput {
+                table_name,
+                key: key_map,
+                ..GetItemInput::default()
+            })
+            .await
+            .ok()
+            .and_then(|output| output.item)
+            .and_then(|attrs| IvrMenu::from_attrs(attrs).ok())
+    }
+}
+
+/// Prompts for a voicemail. The recording is posted back to the current URL
+/// with `call_id` and the menu `option` chosen, if any. `greeting` is said
+/// first.
+pub fn record_twiml(
+    call_id: &Uuid,
+    option: Option<&str>,
+    greeting: Option<&str>,
+    transcription_url: &str,
+) -> String {
+    let twiml = match greeting {
+        Some(greeting) => TwimlResponse::new_response().say(greeting),
+        None => TwimlResponse::new_response(),
+    };
+    take_message(twiml, call_id, option, transcription_url).to_string()
+}
+
+fn take_message(
+    twiml: TwimlResponse,
+    call_id: &Uuid,
+    option: Option<&str>,
+    transcription_url: &str,
+) -> TwimlResponse {
+    twiml.say("Please leave a message at the beep").record(
+        Record::new_record()
+            .action(&call_action(call_id, option, &[]), Method::Post)
+            .play_beep()
+            .transcribe(&callback_url(
+                transcription_url,
+                &[("call_id", call_id.to_string())],
+            )),
+    )
+}
+
+/// Relative URL for Twilio to post back to the current call's handler with,
+/// carrying `call_id`, the menu `option` chosen, if any, and `extra`
+pub fn call_action(call_id: &Uuid, option: Option<&str>, extra: &[(&str, String)]) -> String {
+    let mut params = vec![("call_id", call_id.to_string())];
+    if let Some(option) = option {
+        params.push(("option", option.to_string()));
+    }
+    params.extend_from_slice(extra);
+    callback_url("", &params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_menu() -> IvrMenu {
+        IvrMenu::new_ivr_menu(
+            "+12183957949".to_owned(),
+            "Thanks for calling Acme.".to_owned(),
+            vec![
+                IvrOption {
+                    digit: "1".to_owned(),
+                    label: "billing".to_owned(),
+                    route: "+12183957949#billing".to_owned(),
+                    severity: Severity::Low,
+                },
+                IvrOption {
+                    digit: "2".to_owned(),
+                    label: "outages".to_owned(),
+                    route: "+12183957949#outages".to_owned(),
+                    severity: Severity::Critical,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_menu_twiml() {
+        let menu = test_menu();
+        assert_eq!(menu.option("2").unwrap().severity, Severity::Critical);
+        assert_eq!(menu.option("9"), None);
+
+        let twiml = menu.menu_twiml(
+            "https://example.com/receive_call",
+            &Uuid::nil(),
+            "https://example.com/receive_transcription",
+        );
+        assert!(twiml.contains(
+            r#"<Gather numDigits="1" action="https://example.com/receive_call" method="POST"><Say>Thanks for calling Acme. For billing, press 1. For outages, press 2.</Say></Gather><Say>Please leave a message at the beep</Say>"#
+        ));
+        assert!(twiml.contains(r#"action="?call_id=00000000-0000-0000-0000-000000000000""#));
+
+        let twiml = record_twiml(
+            &Uuid::nil(),
+            Some("2"),
+            None,
+            "https://example.com/receive_transcription",
+        );
+        assert!(twiml.contains("<Response><Say>Please leave a message at the beep</Say>"));
+        assert!(twiml
+            .contains(r#"action="?call_id=00000000-0000-0000-0000-000000000000&amp;option=2""#));
+        assert!(record_twiml(
+            &Uuid::nil(),
+            None,
+            Some("Thanks for calling Acme."),
+            "https://example.com/receive_transcription",
+        )
+        .contains("<Response><Say>Thanks for calling Acme.</Say><Say>Please leave"));
+        assert!(twiml.contains(r#"transcribeCallback="https://example.com/receive_transcription?call_id=00000000-0000-0000-0000-000000000000""#));
+
+        let attrs: dynomite::Attributes = menu.clone().into();
+        assert_eq!(IvrMenu::from_attrs(attrs).unwrap(), menu);
+    }
+}
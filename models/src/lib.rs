@@ -1,8 +1,22 @@
+pub mod archive;
 pub mod call;
+pub mod channel;
+pub mod escalation;
+pub mod forward;
+pub mod group;
+pub mod ivr;
+pub mod notify;
+pub mod queue;
 pub mod range;
 pub mod schedule;
+pub mod slot;
+pub mod sms;
 pub mod time;
+pub mod timeline;
+pub mod twiml;
 pub mod users;
+pub mod validate;
+pub mod webhook;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,436 @@
+use crate::call::Call;
+use crate::channel::{ChannelKind, NotificationChannel, Page};
+use crate::twiml::{Gather, Method, TwimlResponse};
+use crate::webhook::callback_url;
+use async_trait::async_trait;
+use twilio_async::{Twilio, TwilioJson, TwilioRequest};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyError {
+    /// The request never got an answer from the provider
+    Client(String),
+    /// The provider answered but refused to deliver
+    Rejected { code: usize, message: String },
+}
+
+/// Outbound messaging used to page users. `TwilioNotifier` talks to Twilio;
+/// tests and local runs can swap in their own implementation.
+#[async_trait]
+pub trait Notifier {
+    async fn send_sms(&self, from: &str, to: &str, body: &str) -> Result<(), NotifyError>;
+    /// Places a voice call that plays the TwiML served at `twiml_url`
+    async fn place_call(&self, from: &str, to: &str, twiml_url: &str) -> Result<(), NotifyError>;
+}
+
+pub struct TwilioNotifier {
+    twilio: Twilio,
+}
+
+impl TwilioNotifier {
+    pub fn new(sid: String, token: String) -> Result<TwilioNotifier, NotifyError> {
+        Twilio::new(sid, token)
+            .map(|twilio| TwilioNotifier { twilio })
+            .map_err(|e| NotifyError::Client(e.to_string()))
+    }
+}
+
+fn twilio_result<T>(
+    result: Result<TwilioJson<T>, twilio_async::TwilioErr>,
+) -> Result<(), NotifyError> {
+    match result {
+        Ok(TwilioJson::Success(_)) => Ok(()),
+        Ok(TwilioJson::Fail { code, message, .. }) => Err(NotifyError::Rejected { code, message }),
+        Err(e) => Err(NotifyError::Client(e.to_string())),
+    }
+}
+
+#[async_trait]
+impl Notifier for TwilioNotifier {
+    async fn send_sms(&self, from: &str, to: &str, body: &str) -> Result<(), NotifyError> {
+        twilio_result(self.twilio.send_msg(from, to, body).run().await)
+    }
+
+    async fn place_call(&self, from: &str, to: &str, twiml_url: &str) -> Result<(), NotifyError> {
+        twilio_result(self.twilio.call(from, to, twiml_url).run().await)
+    }
+}
+
+/// Page text linking to the voicemail at `recording_url`, which is an
+/// archived copy when there is one
+pub fn sms_body(call: &Call, recording_url: &str) -> String {
+    let length = match call.caller.recording_duration {
+        Some(seconds) => format!(" {}s", seconds),
+        None => String::new(),
+    };
+    let message = match &call.transcription {
+        Some(text) => format!(" Message: \"{}\"", text),
+        None => String::new(),
+    };
+    let group = match &call.menu_option {
+        Some(option) => format!(
+            "{} ({}, {:?} severity)",
+            call.group_id, option.label, option.severity
+        ),
+        None => call.group_id.clone(),
+    };
+    format!(
+        "New page for {}: {} left a{} message.{} Recording: {} Reply ACK {} to acknowledge or RESOLVE {} to resolve.",
+        group,
+        call.caller.describe(&call.phone_number),
+        length,
+        message,
+        recording_url,
+        call.short_code,
+        call.short_code
+    )
+}
+
+/// Follow-up SMS for users who were paged before the voicemail was transcribed
+pub fn transcription_sms_body(call: &Call) -> Option<String> {
+    call.transcription.as_ref().map(|text| {
+        format!(
+            "Transcript for page {}: \"{}\" Reply ACK {} to acknowledge or RESOLVE {} to resolve.",
+            call.short_code, text, call.short_code, call.short_code
+        )
+    })
+}
+
+/// Follow-up SMS for users who were paged before a repeat call from the
+/// same number was transcribed
+pub fn repeat_transcription_sms_body(call: &Call, text: &str) -> String {
+    format!(
+        "Transcript of repeat call for page {}: \"{}\" Reply ACK {} to acknowledge or RESOLVE {} to resolve.",
+        call.short_code, text, call.short_code, call.short_code
+    )
+}
+
+/// A key pressed by a user who picked up a page call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKey {
+    Acknowledge,
+    PlayMessage,
+    Escalate,
+}
+
+impl PageKey {
+    pub fn parse(digits: &str) -> Option<PageKey> {
+        match digits.trim() {
+            "1" => Some(PageKey::Acknowledge),
+            "2" => Some(PageKey::PlayMessage),
+            "3" => Some(PageKey::Escalate),
+            _ => None,
+        }
+    }
+}
+
+/// Link Twilio fetches the page call's TwiML from; `level` is the escalation
+/// level that placed the call, so pressing 3 knows where to go next
+pub fn page_url(base: &str, call: &Call, level: u32) -> String {
+    callback_url(
+        base,
+        &[
+            ("call_id", call.call_id.to_string()),
+            ("level", level.to_string()),
+        ],
+    )
+}
+
+/// TwiML read out to a user who picks up a page call. Key presses are posted
+/// back to `page_url`.
+pub fn page_twiml(call: &Call, page_url: &str) -> String {
+    TwimlResponse::new_response()
+        .gather(
+            Gather::new_gather()
+                .num_digits(1)
+                .action(page_url, Method::Post)
+                .say(&format!(
+                    "New page. A caller at {} left a message. Press 1 to acknowledge, 2 to hear the message, or 3 to escalate.",
+                    spoken_number(&call.phone_number)
+                )),
+        )
+        .say("No input received. Goodbye.")
+        .to_string()
+}
+
+/// Plays the caller's message, then returns to the page menu
+pub fn play_message_twiml(call: &Call, page_url: &str) -> String {
+    TwimlResponse::new_response()
+        .play(&call.message_url)
+        .redirect(page_url, Method::Post)
+        .to_string()
+}
+
+/// Says `text` and hangs up
+pub fn say_twiml(text: &str) -> String {
+    TwimlResponse::new_response().say(text).hangup().to_string()
+}
+
+/// Spaces out the digits so `<Say>` reads a number digit by digit
+fn spoken_number(number: &str) -> String {
+    number
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The outcome of paging one address over one channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub kind: ChannelKind,
+    pub address: String,
+    pub result: Result<(), NotifyError>,
+}
+
+/// Sends `page` to every user on `call` whose notification rules are due
+/// `after` seconds into the step, over each of their addresses for the
+/// channels the rules name. Channels missing from `channels` are skipped.
+/// Returns the outcome of every attempt.
+pub async fn notify_users(
+    channels: &[&(dyn NotificationChannel + Sync)],
+    call: &Call,
+    page: &Page,
+    after: i64,
+) -> Vec<Delivery> {
+    let mut deliveries = Vec::new();
+    for user in &call.users {
+        for kind in user.channels_due(after) {
+            let channel = match channels.iter().find(|channel| channel.kind() == kind) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            for address in user.addresses(kind) {
+                deliveries.push(Delivery {
+                    kind,
+                    address: address.to_string(),
+                    result: channel.deliver(address, page).await,
+                });
+            }
+        }
+    }
+    deliveries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{EmailChannel, SmsChannel, SmtpConfig, VoiceChannel};
+    use crate::ivr::{IvrOption, Severity};
+    use crate::users::{ContactKind, NotificationRule, User};
+    use chrono::Utc;
+    use futures::executor::block_on;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    #[derive(Default)]
+    struct MockNotifier {
+        sent: Mutex<Vec<(String, String, String)>>,
+        fail_to: Option<String>,
+    }
+
+    #[async_trait]
+    impl Notifier for MockNotifier {
+        async fn send_sms(&self, from: &str, to: &str, body: &str) -> Result<(), NotifyError> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((from.to_string(), to.to_string(), body.to_string()));
+            if self.fail_to.as_deref() == Some(to) {
+                Err(NotifyError::Rejected {
+                    code: 21211,
+                    message: "Invalid 'To' Phone Number".to_string(),
+                })
+            } else {
+                Ok(())
+            }
+        }
+
+        async fn place_call(
+            &self,
+            from: &str,
+            to: &str,
+            twiml_url: &str,
+        ) -> Result<(), NotifyError> {
+            self.sent.lock().unwrap().push((
+                from.to_string(),
+                to.to_string(),
+                twiml_url.to_string(),
+            ));
+            Ok(())
+        }
+    }
+
+    fn test_call() -> Call {
+        let mut call = Call::new_call(
+            Uuid::nil(),
+            "+12183957949".to_owned(),
+            "+12183957949".to_owned(),
+            "https://api.twilio.com/recording?a=1&b=2".to_owned(),
+            "+13473513315".to_owned(),
+            Utc::now(),
+        );
+        call.short_code = "4821".to_owned();
+        call.users = vec![
+            User::new_user(
+                "1".to_owned(),
+                "+12183957949".to_owned(),
+                "Tobias Funke".to_owned(),
+                "+19149543303".to_owned(),
+            ),
+            User::new_user(
+                "2".to_owned(),
+                "+12183957949".to_owned(),
+                "Jeff Winger".to_owned(),
+                "+19147251309".to_owned(),
+            ),
+        ];
+        call
+    }
+
+    #[test]
+    fn test_notify_users() {
+        let notifier = MockNotifier {
+            fail_to: Some("+19147251309".to_owned()),
+            ..MockNotifier::default()
+        };
+        let call = test_call();
+        let page = Page::new_page(&call, "https://example.com/page", &call.message_url);
+        let (sms, voice) = (SmsChannel::new(&notifier), VoiceChannel::new(&notifier));
+        let email = EmailChannel::new(SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 1,
+            starttls: false,
+            credentials: None,
+            from: "pager@example.com".to_owned(),
+        });
+        let deliveries = block_on(notify_users(&[&sms, &voice, &email], &call, &page, 0));
+        // Neither user has an email address, so mail is never attempted
+        assert_eq!(deliveries.len(), 4);
+        assert_eq!(
+            deliveries[0],
+            Delivery {
+                kind: ChannelKind::Sms,
+                address: "+19149543303".to_owned(),
+                result: Ok(())
+            }
+        );
+        assert_eq!(deliveries[1].kind, ChannelKind::Voice);
+        assert!(deliveries[2].result.is_err());
+        assert!(deliveries[3].result.is_ok());
+
+        let sent = notifier.sent.lock().unwrap();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(
+            sent[0],
+            (
+                "+12183957949".to_owned(),
+                "+19149543303".to_owned(),
+                sms_body(&call, &call.message_url)
+            )
+        );
+        assert_eq!(sent[1].2, "https://example.com/page");
+        drop(sent);
+
+        // Text straight away, ring both phones after two minutes
+        let mut call = call;
+        call.users[0].add_contact(
+            ContactKind::Phone,
+            "+13475550100".to_owned(),
+            "home".to_owned(),
+        );
+        call.users[0].set_rules(vec![
+            NotificationRule {
+                channel: ChannelKind::Sms,
+                after: 0,
+            },
+            NotificationRule {
+                channel: ChannelKind::Voice,
+                after: 120,
+            },
+        ]);
+        call.users.truncate(1);
+        let deliveries = block_on(notify_users(&[&sms, &voice], &call, &page, 0));
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries.iter().all(|d| d.kind == ChannelKind::Sms));
+        let deliveries = block_on(notify_users(&[&sms, &voice], &call, &page, 120));
+        let rung: Vec<&str> = deliveries.iter().map(|d| d.address.as_str()).collect();
+        assert_eq!(rung, vec!["+19149543303", "+13475550100"]);
+        assert!(deliveries.iter().all(|d| d.kind == ChannelKind::Voice));
+        assert!(block_on(notify_users(&[&sms, &voice], &call, &page, 60)).is_empty());
+    }
+
+    #[test]
+    fn test_page_twiml() {
+        let call = test_call();
+        let url = page_url("https://example.com/page_call", &call, 1);
+        assert_eq!(
+            url,
+            "https://example.com/page_call?call_id=00000000-0000-0000-0000-000000000000&level=1"
+        );
+        let twiml = page_twiml(&call, &url);
+        assert!(twiml.contains("A caller at 1 3 4 7 3 5 1 3 3 1 5 left a message."));
+        assert!(twiml.contains(r#"action="https://example.com/page_call?call_id=00000000-0000-0000-0000-000000000000&amp;level=1""#));
+
+        let twiml = play_message_twiml(&call, &url);
+        assert!(twiml.contains("<Play>https://api.twilio.com/recording?a=1&amp;b=2</Play>"));
+        assert!(twiml.contains("<Redirect method=\"POST\">https://example.com/page_call?"));
+    }
+
+    #[test]
+    fn test_page_key() {
+        assert_eq!(PageKey::parse("1"), Some(PageKey::Acknowledge));
+        assert_eq!(PageKey::parse("2"), Some(PageKey::PlayMessage));
+        assert_eq!(PageKey::parse("3"), Some(PageKey::Escalate));
+        assert_eq!(PageKey::parse("#"), None);
+        assert_eq!(PageKey::parse(""), None);
+    }
+
+    #[test]
+    fn test_sms_body() {
+        let mut call = test_call();
+        assert!(sms_body(&call, &call.message_url)
+            .starts_with("New page for +12183957949: +13473513315 left a message. Recording:"));
+
+        call.menu_option = Some(IvrOption {
+            digit: "2".to_owned(),
+            label: "outages".to_owned(),
+            route: "+12183957949#outages".to_owned(),
+            severity: Severity::Critical,
+        });
+        assert!(sms_body(&call, &call.message_url)
+            .starts_with("New page for +12183957949 (outages, Critical severity): "));
+        call.menu_option = None;
+
+        call.caller.city = Some("BROOKLYN".to_owned());
+        call.caller.recording_duration = Some(42);
+        assert!(sms_body(&call, "https://example.com/recording.mp3").starts_with(
+            "New page for +12183957949: +13473513315 (BROOKLYN) left a 42s message. Recording: https://example.com/recording.mp3 "
+        ));
+    }
+
+    #[test]
+    fn test_transcription_sms() {
+        let mut call = test_call();
+        assert!(!sms_body(&call, &call.message_url).contains("Message:"));
+        assert_eq!(transcription_sms_body(&call), None);
+
+        call.transcription = Some("The database is down".to_owned());
+        assert!(sms_body(&call, &call.message_url)
+            .contains(r#"left a message. Message: "The database is down" Recording:"#));
+        assert_eq!(
+            transcription_sms_body(&call),
+            Some(format!(
+                r#"Transcript for page {0}: "The database is down" Reply ACK {0} to acknowledge or RESOLVE {0} to resolve."#,
+                call.short_code
+            ))
+        );
+        assert_eq!(
+            repeat_transcription_sms_body(&call, "Still down"),
+            format!(
+                r#"Transcript of repeat call for page {0}: "Still down" Reply ACK {0} to acknowledge or RESOLVE {0} to resolve."#,
+                call.short_code
+            )
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use crate::call::Call;
+use chrono::{DateTime, Duration, Utc};
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    MessageAttributeValue, SendMessageError, SendMessageRequest, SendMessageResult, Sqs, SqsClient,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Payload version this code writes and understands
+pub const MESSAGE_VERSION: u32 = 1;
+
+/// The longest delay SQS accepts on a message, in seconds
+pub const MAX_SQS_DELAY: i64 = 900;
+
+/// Asks receive_message to page the next escalation step of a call
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EscalationMessage {
+    pub version: u32,
+    pub call_id: Uuid,
+    pub group_id: String,
+    /// Escalation level the call was at when this was queued
+    pub level: u32,
+    /// The call's attempt count when this was queued, so a delivery for an
+    /// attempt that was already paged can be dropped
+    pub attempt: u32,
+    /// When the step should run. SQS delays at most `MAX_SQS_DELAY`, so a
+    /// message delivered early is queued again until this has passed.
+    pub due: DateTime<Utc>,
+    /// Set on messages that deliver users' later notification rules for the
+    /// step instead of starting the next one: how many seconds into the
+    /// step the rules are for
+    #[serde(default)]
+    pub notify_after: Option<i64>,
+    /// Set on messages that archive the call's recordings instead of paging
+    /// anyone
+    #[serde(default)]
+    pub archive: bool,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Malformed(String),
+    UnsupportedVersion(u32),
+}
+
+impl EscalationMessage {
+    pub fn new_message(call: &Call, due: DateTime<Utc>) -> EscalationMessage {
+        EscalationMessage {
+            version: MESSAGE_VERSION,
+            call_id: call.call_id,
+            group_id: call.group_id.clone(),
+            level: call.level,
+            attempt: call.attempts,
+            due,
+            notify_after: None,
+            archive: false,
+        }
+    }
+
+    /// Delivers the notification rules due `after` seconds into the step
+    /// that paged `level`, unless the call has moved on by then
+    pub fn new_reminder(
+        call: &Call,
+        level: u32,
+        started: DateTime<Utc>,
+        after: i64,
+    ) -> EscalationMessage {
+        EscalationMessage {
+            version: MESSAGE_VERSION,
+            call_id: call.call_id,
+            group_id: call.group_id.clone(),
+            level,
+            attempt: call.attempts,
+            due: started + Duration::seconds(after),
+            notify_after: Some(after),
+            archive: false,
+        }
+    }
+
+    /// Copies whichever of the call's recordings aren't archived yet to S3.
+    /// Failures are retried with the message, and pages link Twilio's copy
+    /// until then.
+    pub fn new_archive(call: &Call, due: DateTime<Utc>) -> EscalationMessage {
+        EscalationMessage {
+            version: MESSAGE_VERSION,
+            call_id: call.call_id,
+            group_id: call.group_id.clone(),
+            level: call.level,
+            attempt: call.attempts,
+            due,
+            notify_after: None,
+            archive: true,
+        }
+    }
+
+    pub fn parse(body: &str) -> Result<EscalationMessage, MessageError> {
+        let message: EscalationMessage =
+            serde_json::from_str(body).map_err(|e| MessageError::Malformed(e.to_string()))?;
+        if message.version != MESSAGE_VERSION {
+            return Err(MessageError::UnsupportedVersion(message.version));
+        }
+        Ok(message)
+    }
+
+    /// Sends the message to `queue_url`, delayed until `due` or as close to
+    /// it as SQS allows
+    pub async fn send(
+        &self,
+        sqs_client: &SqsClient,
+        queue_url: String,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        let mut message_attributes = HashMap::new();
+        for (name, data_type, value) in &[
+            ("version", "Number", self.version.to_string()),
+            ("call_id", "String", self.call_id.to_string()),
+            ("group_id", "String", self.group_id.clone()),
+        ] {
+            message_attributes.insert(
+                name.to_string(),
+                MessageAttributeValue {
+                    data_type: data_type.to_string(),
+                    string_value: Some(value.clone()),
+                    ..MessageAttributeValue::default()
+                },
+            );
+        }
+        sqs_client
+            .send_message(SendMessageRequest {
+                queue_url,
+                delay_seconds: Some(sqs_delay(self.due, Utc::now())),
+                message_body: serde_json::to_string(self).unwrap(),
+                message_attributes: Some(message_attributes),
+                ..SendMessageRequest::default()
+            })
+            .await
+    }
+}
+
+/// Delay to send a message with so it arrives at `due`, or as close to it
+/// as SQS allows
+pub fn sqs_delay(due: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    (due - now).num_seconds().max(0).min(MAX_SQS_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqs_delay() {
+        let now: DateTime<Utc> = "2020-06-01T09:00:00Z".parse().unwrap();
+        assert_eq!(sqs_delay(now + Duration::seconds(50), now), 50);
+        assert_eq!(sqs_delay(now + Duration::minutes(30), now), MAX_SQS_DELAY);
+        assert_eq!(sqs_delay(now - Duration::seconds(5), now), 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut call = Call::new_call(
+            Uuid::nil(),
+            "+12183957949".to_owned(),
+            "+12183957949".to_owned(),
+            "https://api.twilio.com/recording".to_owned(),
+            "+13473513315".to_owned(),
+            "2020-06-01T09:00:00Z".parse().unwrap(),
+        );
+        call.level = 1;
+        call.attempts = 3;
+        let message =
+            EscalationMessage::new_message(&call, "2020-06-01T09:30:00Z".parse().unwrap());
+        let body = serde_json::to_string(&message).unwrap();
+        assert_eq!(EscalationMessage::parse(&body).unwrap(), message);
+        assert_eq!(message.attempt, 3);
+
+        // Messages queued before reminders or archiving existed still parse
+        let old = body
+            .replace(",\"notify_after\":null", "")
+            .replace(",\"archive\":false", "");
+        assert!(!old.contains("notify_after") && !old.contains("archive"));
+        assert_eq!(EscalationMessage::parse(&old).unwrap(), message);
+        let archive =
+            EscalationMessage::new_archive(&call, "2020-06-01T09:30:00Z".parse().unwrap());
+        let body = serde_json::to_string(&archive).unwrap();
+        assert!(EscalationMessage::parse(&body).unwrap().archive);
+
+        let reminder =
+            EscalationMessage::new_reminder(&call, 0, "2020-06-01T09:30:00Z".parse().unwrap(), 120);
+        assert_eq!(
+            reminder.due,
+            "2020-06-01T09:32:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(reminder.level, 0);
+        let body = serde_json::to_string(&reminder).unwrap();
+        assert_eq!(
+            EscalationMessage::parse(&body).unwrap().notify_after,
+            Some(120)
+        );
+
+        assert!(matches!(
+            EscalationMessage::parse(&body.replace("\"version\":1", "\"version\":2")),
+            Err(MessageError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            EscalationMessage::parse("00000000-0000-0000-0000-000000000000"),
+            Err(MessageError::Malformed(_))
+        ));
+    }
+}
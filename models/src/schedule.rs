@@ -1,7 +1,10 @@
 use crate::range::{ClosedRange, OpenRange, Range};
 use crate::time::TimeOfDayDuration;
 use crate::users::User;
-use chrono::{offset::FixedOffset, DateTime, NaiveDateTime};
+use chrono::{
+    offset::{FixedOffset, Offset, Utc},
+    DateTime, NaiveDateTime,
+};
 use dynomite::{
     dynamodb::{
         AttributeValue, DynamoDb, DynamoDbClient, GetItemInput, PutItemError, PutItemInput,
@@ -223,7 +226,22 @@ impl Schedule {
         Ok(())
     }
 
-    #[tokio::main]
+    /// `write_schedule` for callers already running on a runtime
+    pub async fn async_write_schedule(
+        &self,
+        table_name: String,
+        client: &DynamoDbClient,
+    ) -> Result<(), RusotoError<PutItemError>> {
+        client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(),
+                ..PutItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
     pub async fn get_schedule(
         table_name: String,
         region: Region,
@@ -245,12 +263,51 @@ impl Schedule {
             .map_or_else(|| None, |attrs| Schedule::from_attrs(attrs).ok())
     }
 
+    /// Everyone who is on call at any point in the schedule
+    pub fn members(&self) -> Vec<User> {
+        let mut members: Vec<User> = Vec::new();
+        for user in self.entries.iter().flat_map(|entry| entry.providers.iter()) {
+            if !members.contains(user) {
+                members.push(user.clone());
+            }
+        }
+        members
+    }
+
     pub fn get_providers(&self, date_time: DateTime<FixedOffset>) -> Option<Vec<User>> {
         self.entries
             .iter()
             .find(|entry| entry.range.contains(Some(&date_time)))
             .map_or_else(|| None, |entry| Some(entry.providers.to_owned()))
     }
+
+    /// The offset the schedule was generated in, UTC if it has no entries
+    pub fn offset(&self) -> FixedOffset {
+        self.entries
+            .first()
+            .map_or_else(|| Utc.fix(), |entry| *entry.range.start.offset())
+    }
+
+    /// The first time after `date_time` at which whoever is on call changes
+    pub fn next_handoff(&self, date_time: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+        self.entries
+            .iter()
+            .flat_map(|entry| vec![entry.range.start, entry.range.end])
+            .filter(|bound| *bound > date_time)
+            .min()
+    }
+
+    /// The providers of the first entry starting after `date_time`, with its start
+    pub fn next_providers(
+        &self,
+        date_time: DateTime<FixedOffset>,
+    ) -> Option<(DateTime<FixedOffset>, Vec<User>)> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.range.start > date_time)
+            .min_by_key(|entry| entry.range.start)
+            .map(|entry| (entry.range.start, entry.providers.to_owned()))
+    }
 }
 
 #[cfg(test)]
@@ -261,7 +318,7 @@ mod tests {
     use crate::users::User;
     use chrono::{FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
     use rusoto_core::Region;
-    use NaiveTime::from_hms;
+    fn from_hms(h: u32, m: u32, s: u32) -> NaiveTime { NaiveTime::from_hms(h, m, s) }
 
     #[test]
     fn test_schedule() {
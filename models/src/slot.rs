@@ -0,0 +1,351 @@
+use crate::group::Group;
+use crate::range::OpenRange;
+use crate::schedule::{generate_schedule, Schedule, ScheduleSlot};
+use crate::time::TimeOfDayDuration;
+use crate::validate::ValidationError;
+use chrono::{offset::FixedOffset, DateTime, Duration};
+use dynomite::{
+    dynamodb::{
+        DeleteItemError, DeleteItemInput, DynamoDb, DynamoDbClient, GetItemInput, PutItemError,
+        PutItemInput, QueryInput,
+    },
+    Attribute, FromAttributes, Item,
+};
+use rusoto_core::RusotoError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Longest window a schedule is generated for at once, which keeps the
+/// stored schedule well under DynamoDB's item size limit
+pub const MAX_WINDOW_DAYS: i64 = 92;
+
+/// A `ScheduleSlot` kept for a group. Providers are stored as member ids
+/// and looked up when the schedule is generated, so changes to members
+/// carry over.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Item)]
+pub struct GroupSlot {
+    #[dynomite(partition_key)]
+    pub group_id: String,
+    #[dynomite(sort_key)]
+    pub slot_id: Uuid,
+    pub start: DateTime<FixedOffset>,
+    /// When the slot stops applying, if ever
+    pub end: Option<DateTime<FixedOffset>>,
+    pub restriction: TimeOfDayDuration,
+    pub provider_ids: Vec<String>,
+}
+
+impl GroupSlot {
+    pub fn new_group_slot(
+        group_id: String,
+        start: DateTime<FixedOffset>,
+        end: Option<DateTime<FixedOffset>>,
+        restriction: TimeOfDayDuration,
+        provider_ids: Vec<String>,
+    ) -> GroupSlot {
+        GroupSlot {
+            group_id,
+            slot_id: Uuid::new_v4(),
+            start,
+            end,
+            restriction,
+            provider_ids,
+        }
+    }
+
+    /// Checks the slot makes sense for `group` before it is stored
+    pub fn validate(&self, group: &Group) -> Result<(), ValidationError> {
+        if matches!(self.end, Some(end) if end <= self.start) {
+            return Err(ValidationError::new("end", "must be after start"));
+        }
+        if self.restriction.start().day_of_week().is_some()
+            != self.restriction.end().day_of_week().is_some()
+        {
+            return Err(ValidationError::new(
+                "restriction",
+                "start and end must both have a day_of_week or neither",
+            ));
+        }
+        if self.provider_ids.is_empty() {
+            return Err(ValidationError::new("provider_ids", "must not be empty"));
+        }
+        for (i, provider_id) in self.provider_ids.iter().enumerate() {
+            if !group.members.iter().any(|user| user.uuid() == provider_id) {
+                return Err(ValidationError::new(
+                    &format!("provider_ids[{}]", i),
+                    "must be a member of the group",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The slot in the group's local time, with providers who have since
+    /// left the group dropped
+    pub fn to_schedule_slot(&self, group: &Group) -> ScheduleSlot {
+        let offset = group.offset();
+        let providers = self
+            .provider_ids
+            .iter()
+            .filter_map(|provider_id| {
+                group
+                    .members
+                    .iter()
+                    .find(|user| user.uuid() == provider_id)
+                    .cloned()
+            })
+            .collect();
+        ScheduleSlot::new_schedule_slot(
+            OpenRange::new_open_range(
+                &self.start.with_timezone(&offset).naive_local(),
+                &self.end.map(|end| end.with_timezone(&offset).naive_local()),
+            ),
+            self.restriction.clone(),
+            providers,
+        )
+    }
+
+    pub async fn write_slot(
+        &self,
+        table_name: String,
+        client: &DynamoDbClient,
+    ) -> Result<(), RusotoError<PutItemError>> {
+        client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(),
+                ..PutItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_slot(
+        &self,
+        table_name: String,
+        client: &DynamoDbClient,
+    ) -> Result<(), RusotoError<DeleteItemError>> {
+        client
+            .delete_item(DeleteItemInput {
+                table_name,
+                key: self.key(),
+                ..DeleteItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_slot(
+        table_name: String,
+        client: &DynamoDbClient,
+        group_id: String,
+        slot_id: Uuid,
+    ) -> Option<GroupSlot> {
+        let mut key_map = HashMap::new();
+        key_map.insert("group_id".to_string(), group_id.into_attr());
+        key_map.insert("slot_id".to_string(), slot_id.into_attr());
+        client
+            .get_item(GetItemInput {
+                table_name,
+                key: key_map,
+                ..GetItemInput::default()
+            })
+            .await
+            .ok()
+            .and_then(|output| output.item)
+            .and_then(|attrs| GroupSlot::from_attrs(attrs).ok())
+    }
+
+    /// Every slot kept for `group_id`
+    pub async fn get_slots(
+        table_name: String,
+        client: &DynamoDbClient,
+        group_id: String,
+    ) -> Option<Vec<GroupSlot>> {
+        let mut values = HashMap::new();
+        values.insert(":group_id".to_string(), group_id.into_attr());
+        let mut slots = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = client
+                .query(QueryInput {
+                    table_name: table_name.clone(),
+                    key_condition_expression: Some("group_id = :group_id".to_string()),
+                    expression_attribute_values: Some(values.clone()),
+                    exclusive_start_key: start_key,
+                    ..QueryInput::default()
+                })
+                .await
+                .ok()?;
+            for attrs in output.items.unwrap_or_default() {
+                slots.push(GroupSlot::from_attrs(attrs).ok()?);
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Some(slots);
+            }
+        }
+    }
+}
+
+/// Checks a window a schedule is asked to be generated for
+pub fn check_window(
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+) -> Result<(), ValidationError> {
+    if end <= start {
+        return Err(ValidationError::new("end", "must be after start"));
+    }
+    if end - start > Duration::days(MAX_WINDOW_DAYS) {
+        return Err(ValidationError::new(
+            "end",
+            &format!("must be at most {} days after start", MAX_WINDOW_DAYS),
+        ));
+    }
+    Ok(())
+}
+
+/// The group's schedule between `start` and `end`, generated from its slots
+/// in its own time zone
+pub fn regenerate_schedule(
+    group: &Group,
+    slots: &[GroupSlot],
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+) -> Schedule {
+    let offset = group.offset();
+    generate_schedule(
+        slots
+            .iter()
+            .map(|slot| slot.to_schedule_slot(group))
+            .collect(),
+        start.with_timezone(&offset).naive_local(),
+        end.with_timezone(&offset).naive_local(),
+        offset,
+        group.group_id.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::User;
+
+    fn test_group() -> Group {
+        let mut group = Group::new_group(
+            "acme-support".to_owned(),
+            "Acme Support".to_owned(),
+            -4 * 3600,
+            vec!["+12183957949".to_owned()],
+        );
+        group.members.push(User::new_user(
+            "2".to_owned(),
+            "acme-support".to_owned(),
+            "Jeff Winger".to_owned(),
+            "+19147251309".to_owned(),
+        ));
+        group
+    }
+
+    fn test_slot() -> GroupSlot {
+        let restriction = serde_json::from_str(
+            r#"{"start": {"time": "09:00:00"}, "end": {"time": "17:00:00", "day_of_week": null}}"#,
+        )
+        .unwrap();
+        GroupSlot::new_group_slot(
+            "acme-support".to_owned(),
+            "2020-06-01T00:00:00-04:00".parse().unwrap(),
+            Some("2020-06-03T00:00:00-04:00".parse().unwrap()),
+            restriction,
+            vec!["2".to_owned()],
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let group = test_group();
+        let slot = test_slot();
+        assert_eq!(slot.validate(&group), Ok(()));
+        let field = |slot: &GroupSlot| slot.validate(&group).unwrap_err().field;
+
+        let mut bad = slot.clone();
+        bad.end = Some(bad.start);
+        assert_eq!(field(&bad), "end");
+
+        let mut bad = slot.clone();
+        bad.restriction = serde_json::from_str(
+            r#"{"start": {"time": "09:00:00", "day_of_week": "Mon"}, "end": {"time": "17:00:00"}}"#,
+        )
+        .unwrap();
+        assert_eq!(field(&bad), "restriction");
+
+        let mut bad = slot;
+        bad.provider_ids.push("9".to_owned());
+        assert_eq!(field(&bad), "provider_ids[1]");
+
+        let start: DateTime<FixedOffset> = "2020-06-01T00:00:00-04:00".parse().unwrap();
+        assert_eq!(check_window(start, start + Duration::days(30)), Ok(()));
+        assert!(check_window(start, start).is_err());
+        assert!(check_window(start, start + Duration::days(MAX_WINDOW_DAYS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_regenerate_schedule() {
+        let mut group = test_group();
+        let slot = test_slot();
+        let schedule = regenerate_schedule(
+            &group,
+            std::slice::from_ref(&slot),
+            "2020-06-01T00:00:00-04:00".parse().unwrap(),
+            "2020-06-08T00:00:00-04:00".parse().unwrap(),
+        );
+        let on_call = |schedule: &Schedule, at: &str| {
+            schedule
+                .get_providers(at.parse().unwrap())
+                .map(|users| users.iter().map(|user| user.name().to_owned()).collect())
+        };
+        assert_eq!(
+            on_call(&schedule, "2020-06-02T10:00:00-04:00"),
+            Some(vec!["Jeff Winger".to_owned()])
+        );
+        assert_eq!(on_call(&schedule, "2020-06-02T18:00:00-04:00"), None);
+        assert_eq!(on_call(&schedule, "2020-06-04T10:00:00-04:00"), None);
+
+        // Providers who left the group are dropped
+        group.members.clear();
+        let schedule = regenerate_schedule(
+            &group,
+            &[slot],
+            "2020-06-01T00:00:00-04:00".parse().unwrap(),
+            "2020-06-08T00:00:00-04:00".parse().unwrap(),
+        );
+        assert_eq!(
+            on_call(&schedule, "2020-06-02T10:00:00-04:00"),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_stored_slot() {
+        let slot = test_slot();
+        let json = serde_json::to_value(&slot.restriction).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "start": {"time": "09:00:00", "day_of_week": null},
+                "end": {"time": "17:00:00", "day_of_week": null},
+            })
+        );
+        let attrs: dynomite::Attributes = slot.clone().into();
+        assert_eq!(GroupSlot::from_attrs(attrs).unwrap(), slot);
+
+        let mut weekly = slot;
+        weekly.restriction = serde_json::from_str(
+            r#"{"start": {"time": "12:00:00", "day_of_week": "Mon"}, "end": {"time": "22:00:00", "day_of_week": "Monday"}}"#,
+        )
+        .unwrap();
+        let attrs: dynomite::Attributes = weekly.clone().into();
+        assert_eq!(GroupSlot::from_attrs(attrs).unwrap(), weekly);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::schedule::Schedule;
+use crate::twiml::TwimlResponse;
+use crate::users::User;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveTime, Timelike, Weekday};
+
+/// A reply texted back by a paged user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmsCommand {
+    Ack(String),
+    Resolve(String),
+}
+
+impl SmsCommand {
+    /// Parses replies like "ACK 4821" or "resolve 4821"
+    pub fn parse(body: &str) -> Option<SmsCommand> {
+        let mut words = body.split_whitespace();
+        let command = words.next()?.to_uppercase();
+        let short_code = words.next()?;
+        if words.next().is_some() || !short_code.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        match command.as_str() {
+            "ACK" => Some(SmsCommand::Ack(short_code.to_string())),
+            "RESOLVE" => Some(SmsCommand::Resolve(short_code.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn short_code(&self) -> &str {
+        match self {
+            SmsCommand::Ack(code) | SmsCommand::Resolve(code) => code,
+        }
+    }
+}
+
+/// The day a "WHO" query asks about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryDay {
+    Today,
+    Tomorrow,
+    /// The next such day, today included
+    Weekday(Weekday),
+}
+
+/// "Who is on call" asked by text, e.g. "WHO", "WHO 5pm" or "WHO tomorrow 9am"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WhoQuery {
+    pub day: Option<QueryDay>,
+    pub time: Option<NaiveTime>,
+}
+
+impl WhoQuery {
+    pub fn parse(body: &str) -> Option<WhoQuery> {
+        let mut words = body.split_whitespace();
+        if words.next()?.to_uppercase() != "WHO" {
+            return None;
+        }
+        let mut query = WhoQuery {
+            day: None,
+            time: None,
+        };
+        for word in words {
+            let word = word.to_lowercase();
+            match (parse_day(&word), parse_time(&word)) {
+                (Some(day), _) if query.day.is_none() => query.day = Some(day),
+                (_, Some(time)) if query.time.is_none() => query.time = Some(time),
+                _ => return None,
+            }
+        }
+        Some(query)
+    }
+
+    /// When the query asks about, in `now`'s offset. A bare time that has
+    /// already passed today means tomorrow, and a day with no time means
+    /// that day at the current time.
+    pub fn at(&self, now: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let days = match self.day {
+            None => match self.time {
+                Some(time) if time < now.time() => 1,
+                _ => 0,
+            },
+            Some(QueryDay::Today) => 0,
+            Some(QueryDay::Tomorrow) => 1,
+            Some(QueryDay::Weekday(weekday)) => {
+                (7 + weekday.num_days_from_monday() as i64
+                    - now.weekday().num_days_from_monday() as i64)
+                    % 7
+            }
+        };
+        let at = now + Duration::days(days);
+        match self.time {
+            Some(time) => at
+                .with_hour(time.hour())
+                .and_then(|at| at.with_minute(time.minute()))
+                .and_then(|at| at.with_second(0))
+                .and_then(|at| at.with_nanosecond(0))
+                .unwrap_or(at),
+            None => at,
+        }
+    }
+}
+
+fn parse_day(word: &str) -> Option<QueryDay> {
+    match word {
+        "today" => Some(QueryDay::Today),
+        "tomorrow" => Some(QueryDay::Tomorrow),
+        _ => word.parse::<Weekday>().ok().map(QueryDay::Weekday),
+    }
+}
+
+/// Reads times like "9am", "9:30pm", "17:00" or "noon"
+fn parse_time(word: &str) -> Option<NaiveTime> {
+    if word == "noon" {
+        return NaiveTime::from_hms_opt(12, 0, 0);
+    }
+    let (clock, pm) = match word.get(word.len().saturating_sub(2)..) {
+        Some("am") => (&word[..word.len() - 2], Some(false)),
+        Some("pm") => (&word[..word.len() - 2], Some(true)),
+        _ => (word, None),
+    };
+    let mut parts = clock.splitn(2, ':');
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = match parts.next() {
+        Some(minute) if minute.len() == 2 => minute.parse().ok()?,
+        Some(_) => return None,
+        // A bare number is only a time with am/pm after it
+        None if pm.is_none() => return None,
+        None => 0,
+    };
+    let hour = match pm {
+        Some(_) if hour == 0 || hour > 12 => return None,
+        Some(false) => hour % 12,
+        Some(true) => hour % 12 + 12,
+        None => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+}
+
+fn format_providers(providers: &[User]) -> String {
+    providers
+        .iter()
+        .map(|user| format!("{} ({})", user.name(), user.number))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Answers a "WHO" query with who is on call at `at` and when that next
+/// changes, or failing that who is on call next
+pub fn who_reply(schedule: &Schedule, at: DateTime<FixedOffset>) -> String {
+    let format = "%a %b %-d %H:%M";
+    match schedule.get_providers(at) {
+        Some(providers) if !providers.is_empty() => {
+            let mut reply = format!(
+                "On call {}: {}.",
+                at.format(format),
+                format_providers(&providers)
+            );
+            if let Some(handoff) = schedule.next_handoff(at) {
+                reply.push_str(&format!(" Next handoff {}.", handoff.format(format)));
+            }
+            reply
+        }
+        _ => match schedule.next_providers(at) {
+            Some((start, providers)) => format!(
+                "Nobody is on call {}. Next on call from {}: {}.",
+                at.format(format),
+                start.format(format),
+                format_providers(&providers)
+            ),
+            None => format!("Nobody is on call {} or after.", at.format(format)),
+        },
+    }
+}
+
+/// TwiML answering an inbound SMS with `text`
+pub fn reply_twiml(text: &str) -> String {
+    TwimlResponse::new_response().message(text).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::range::OpenRange;
+    use crate::schedule::{generate_schedule, ScheduleSlot};
+    use crate::time::{TimeOfDay, TimeOfDayDuration};
+    use chrono::{NaiveDate, TimeZone};
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    /// A time in June 2020, Eastern
+    fn june(day: u32, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+        let est = FixedOffset::east_opt(-4 * 3600).unwrap();
+        let date = NaiveDate::from_ymd_opt(2020, 6, day).unwrap();
+        est.from_local_datetime(&date.and_time(time(hour, minute)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SmsCommand::parse("ACK 4821"),
+            Some(SmsCommand::Ack("4821".to_owned()))
+        );
+        assert_eq!(
+            SmsCommand::parse("  resolve   0042 "),
+            Some(SmsCommand::Resolve("0042".to_owned()))
+        );
+        assert_eq!(SmsCommand::parse("ACK"), None);
+        assert_eq!(SmsCommand::parse("ACK abcd"), None);
+        assert_eq!(SmsCommand::parse("ACK 4821 please"), None);
+        assert_eq!(SmsCommand::parse("hello 4821"), None);
+        assert_eq!(SmsCommand::parse(""), None);
+    }
+
+    #[test]
+    fn test_who_query() {
+        let who = |day, time| WhoQuery { day, time };
+        assert_eq!(WhoQuery::parse("who"), Some(who(None, None)));
+        assert_eq!(
+            WhoQuery::parse("WHO tomorrow 9am"),
+            Some(who(Some(QueryDay::Tomorrow), Some(time(9, 0))))
+        );
+        assert_eq!(
+            WhoQuery::parse("Who 12:30am Fri"),
+            Some(who(
+                Some(QueryDay::Weekday(Weekday::Fri)),
+                Some(time(0, 30))
+            ))
+        );
+        assert_eq!(
+            WhoQuery::parse("WHO 17:00"),
+            Some(who(None, Some(time(17, 0))))
+        );
+        assert_eq!(WhoQuery::parse("WHO 9"), None);
+        assert_eq!(WhoQuery::parse("WHO 13pm"), None);
+        assert_eq!(WhoQuery::parse("WHO today tomorrow"), None);
+        assert_eq!(WhoQuery::parse("WHOM"), None);
+        assert_eq!(WhoQuery::parse("ACK 4821"), None);
+
+        // Tuesday June 2nd 2020, 3pm
+        let now = june(2, 15, 0);
+        assert_eq!(who(None, None).at(now), now);
+        assert_eq!(who(None, Some(time(9, 0))).at(now), june(3, 9, 0));
+        assert_eq!(who(None, Some(time(17, 0))).at(now), june(2, 17, 0));
+        assert_eq!(who(Some(QueryDay::Tomorrow), None).at(now), june(3, 15, 0));
+        assert_eq!(
+            who(Some(QueryDay::Weekday(Weekday::Mon)), Some(time(9, 0))).at(now),
+            june(8, 9, 0)
+        );
+    }
+
+    #[test]
+    fn test_who_reply() {
+        let jeff = User::new_user(
+            "2".to_owned(),
+            "+12183957949".to_owned(),
+            "Jeff Winger".to_owned(),
+            "+19147251309".to_owned(),
+        );
+        let june1 = june(1, 0, 0).naive_local();
+        let june3 = june(3, 0, 0).naive_local();
+        let nine_to_five = TimeOfDayDuration::new_todd(
+            TimeOfDay::new_tod(time(9, 0), None),
+            TimeOfDay::new_tod(time(17, 0), None),
+        );
+        let schedule = generate_schedule(
+            vec![ScheduleSlot::new_schedule_slot(
+                OpenRange::new_open_range(&june1, &Some(june3)),
+                nine_to_five,
+                vec![jeff],
+            )],
+            june1,
+            june3,
+            june(1, 0, 0).timezone(),
+            "+12183957949".to_owned(),
+        );
+        assert_eq!(schedule.offset(), june(1, 0, 0).timezone());
+        assert_eq!(
+            who_reply(&schedule, june(1, 10, 0)),
+            "On call Mon Jun 1 10:00: Jeff Winger (+19147251309). Next handoff Mon Jun 1 17:00."
+        );
+        assert_eq!(
+            who_reply(&schedule, june(1, 20, 0)),
+            "Nobody is on call Mon Jun 1 20:00. Next on call from Tue Jun 2 09:00: Jeff Winger (+19147251309)."
+        );
+        assert_eq!(
+            who_reply(&schedule, june(2, 18, 0)),
+            "Nobody is on call Tue Jun 2 18:00 or after."
+        );
+    }
+
+    #[test]
+    fn test_reply_twiml() {
+        assert_eq!(
+            reply_twiml("Call 4821 & co"),
+            r#"<?xml version="1.0" encoding="UTF-8"?><Response><Message>Call 4821 &amp; co</Message></Response>"#
+        );
+    }
+}
@@ -4,12 +4,19 @@ use chrono::{
     offset::FixedOffset, DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime,
     TimeZone, Weekday,
 };
-use std::iter::{successors, Iterator};
+use dynomite::{dynamodb::AttributeValue, error::AttributeError, Attribute};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::iter::{successors, Iterator};
 
-#[derive(Clone, Debug)]
+/// A time of day, every day or on one day of the week. In JSON:
+/// `{"time": "09:00:00", "day_of_week": "Mon"}`, with a null or missing
+/// `day_of_week` for every day.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TimeOfDay {
     time: NaiveTime,
+    #[serde(default)]
     day_of_week: Option<Weekday>,
 }
 
@@ -17,6 +24,46 @@ impl TimeOfDay {
     pub fn new_tod(time: NaiveTime, day_of_week: Option<Weekday>) -> TimeOfDay {
         TimeOfDay { time, day_of_week }
     }
+
+    pub fn day_of_week(&self) -> Option<Weekday> {
+        self.day_of_week
+    }
+}
+
+impl Attribute for TimeOfDay {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert(
+            "time".to_string(),
+            self.time.format("%H:%M:%S").to_string().into_attr(),
+        );
+        map.insert(
+            "day_of_week".to_string(),
+            self.day_of_week.map(|day| day.to_string()).into_attr(),
+        );
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        let mut m = value.m.ok_or(AttributeError::InvalidType)?;
+        let time = String::from_attr(m.remove("time").ok_or(AttributeError::MissingField {
+            name: "time".to_string(),
+        })?)?;
+        let day_of_week = match m.remove("day_of_week") {
+            Some(day) => Option::<String>::from_attr(day)?,
+            None => None,
+        };
+        Ok(TimeOfDay {
+            time: time.parse().map_err(|_e| AttributeError::InvalidFormat)?,
+            day_of_week: match day_of_week {
+                Some(day) => Some(day.parse().map_err(|_e| AttributeError::InvalidFormat)?),
+                None => None,
+            },
+        })
+    }
 }
 fn first_weekday_after(dt: DateTime<FixedOffset>, wd: Weekday) -> DateTime<FixedOffset> {
     let dt_since = dt.weekday().num_days_from_monday() as i64;
@@ -44,16 +91,16 @@ impl TimeOfDay {
                 Box::new(succ)
             }
             None => {
-                let succ = successors(Some(now_date), |date| {
-                    Some(*date + Duration::days(1))
-                });
+                let succ = successors(Some(now_date), |date| Some(*date + Duration::days(1)));
                 Box::new(succ)
             }
         }
     }
 }
 
-#[derive(Clone, Debug)]
+/// A stretch of each day or week, from `start` until the next `end`. In
+/// JSON: `{"start": <TimeOfDay>, "end": <TimeOfDay>}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TimeOfDayDuration {
     start: TimeOfDay,
     end: TimeOfDay,
@@ -63,6 +110,14 @@ impl TimeOfDayDuration {
     pub fn new_todd(start: TimeOfDay, end: TimeOfDay) -> TimeOfDayDuration {
         TimeOfDayDuration { start, end }
     }
+
+    pub fn start(&self) -> &TimeOfDay {
+        &self.start
+    }
+
+    pub fn end(&self) -> &TimeOfDay {
+        &self.end
+    }
 }
 
 impl TimeOfDayDuration {
@@ -77,13 +132,13 @@ impl TimeOfDayDuration {
             Some(d) => Some(*d),
             None => None,
         };
-        let end_succ =
-            self.end
-                .to_succ(now.date(), offset)
-                .skip_while(move |x| match start {
-                    Some(p) => x < &p,
-                    None => true,
-                });
+        let end_succ = self
+            .end
+            .to_succ(now.date(), offset)
+            .skip_while(move |x| match start {
+                Some(p) => x < &p,
+                None => true,
+            });
         let succ = self
             .start
             .to_succ(now.date(), offset)
@@ -104,6 +159,35 @@ impl TimeOfDayDuration {
     }
 }
 
+impl Attribute for TimeOfDayDuration {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("start".to_string(), self.start.into_attr());
+        map.insert("end".to_string(), self.end.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(TimeOfDayDuration {
+                    start: TimeOfDay::from_attr(field("start")?)?,
+                    end: TimeOfDay::from_attr(field("end")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,7 +213,7 @@ mod tests {
             day_of_week: None,
         };
         assert_eq!(
-            nine_daily.to_succ(&d1, &est).take(3).collect::<Vec<_>>(),
+            nine_daily.to_succ(d1, est).take(3).collect::<Vec<_>>(),
             vec![
                 est.from_local_datetime(&dt1).unwrap(),
                 est.from_local_datetime(&dt2).unwrap(),
@@ -158,7 +242,7 @@ mod tests {
             day_of_week: Some(Weekday::Wed),
         };
         assert_eq!(
-            nine_daily.to_succ(&d1, &est).take(3).collect::<Vec<_>>(),
+            nine_daily.to_succ(d1, est).take(3).collect::<Vec<_>>(),
             vec![
                 est.from_local_datetime(&dt2).unwrap(),
                 est.from_local_datetime(&dt3).unwrap(),
@@ -187,7 +271,7 @@ mod tests {
             day_of_week: Some(Weekday::Wed),
         };
         assert_eq!(
-            nine_daily.to_succ(&d1, &est).take(3).collect::<Vec<_>>(),
+            nine_daily.to_succ(d1, est).take(3).collect::<Vec<_>>(),
             vec![
                 est.from_local_datetime(&dt2).unwrap(),
                 est.from_local_datetime(&dt3).unwrap(),
@@ -241,7 +325,7 @@ mod tests {
         };
 
         assert_eq!(
-            todd.to_iter(&dt1, &est).take(3).collect::<Vec<_>>(),
+            todd.to_iter(&dt1, est).take(3).collect::<Vec<_>>(),
             vec![
                 ClosedRange {
                     start: est.from_local_datetime(&dt2).unwrap(),
@@ -0,0 +1,163 @@
+use chrono::{DateTime, Utc};
+use dynomite::{
+    dynamodb::{DynamoDb, DynamoDbClient, PutItemError, PutItemInput, QueryInput},
+    Attribute, FromAttributes, Item,
+};
+use log::warn;
+use rusoto_core::{Region, RusotoError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Attribute)]
+pub enum EventKind {
+    Created,
+    RepeatCall,
+    Archived,
+    Transcribed,
+    Notified,
+    DeliveryFailed,
+    Requeued,
+    Escalated,
+    Acknowledged,
+    Resolved,
+    Expired,
+}
+
+/// One entry in a call's append-only history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Item)]
+pub struct TimelineEvent {
+    #[dynomite(partition_key)]
+    pub call_id: Uuid,
+    /// Orders events within a call: the time they happened plus a random
+    /// suffix so two events in the same microsecond don't collide
+    #[dynomite(sort_key)]
+    pub event_id: String,
+    pub at: DateTime<Utc>,
+    pub kind: EventKind,
+    pub detail: String,
+}
+
+impl TimelineEvent {
+    pub fn new_event(
+        call_id: Uuid,
+        at: DateTime<Utc>,
+        kind: EventKind,
+        detail: String,
+    ) -> TimelineEvent {
+        TimelineEvent {
+            call_id,
+            event_id: format!(
+                "{}#{}",
+                at.format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+                &Uuid::new_v4().to_string()[..8]
+            ),
+            at,
+            kind,
+            detail,
+        }
+    }
+
+    pub async fn write_event(
+        &self,
+        table_name: String,
+        region: Region,
+    ) -> Result<(), RusotoError<PutItemError>> {
+        let client = DynamoDbClient::new(region);
+        client
+            .put_item(PutItemInput {
+                table_name,
+                item: self.clone().into(),
+                // Events are never overwritten
+                condition_expression: Some("attribute_not_exists(event_id)".to_string()),
+                ..PutItemInput::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Records an event that happened now. The timeline is a diagnostic aid,
+    /// so a failed write is logged rather than failing the caller.
+    pub async fn append(
+        table_name: String,
+        region: Region,
+        call_id: Uuid,
+        kind: EventKind,
+        detail: String,
+    ) {
+        let event = TimelineEvent::new_event(call_id, Utc::now(), kind, detail);
+        if let Err(e) = event.write_event(table_name, region).await {
+            warn!(
+                "Timeline write for {} failed: {:?} ({:?})",
+                call_id, e, event
+            );
+        }
+    }
+
+    /// Every event recorded for `call_id`, oldest first
+    pub async fn get_timeline(
+        table_name: String,
+        region: Region,
+        call_id: Uuid,
+    ) -> Option<Vec<TimelineEvent>> {
+        let client = DynamoDbClient::new(region);
+        let mut values = HashMap::new();
+        values.insert(":call_id".to_string(), call_id.into_attr());
+        let mut events = Vec::new();
+        let mut start_key = None;
+        loop {
+            let output = client
+                .query(QueryInput {
+                    table_name: table_name.clone(),
+                    key_condition_expression: Some("call_id = :call_id".to_string()),
+                    expression_attribute_values: Some(values.clone()),
+                    exclusive_start_key: start_key,
+                    scan_index_forward: Some(true),
+                    ..QueryInput::default()
+                })
+                .await
+                .ok()?;
+            for attrs in output.items.unwrap_or_default() {
+                events.push(TimelineEvent::from_attrs(attrs).ok()?);
+            }
+            start_key = output.last_evaluated_key;
+            if start_key.is_none() {
+                return Some(events);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_ordering() {
+        let call_id = Uuid::nil();
+        let first = TimelineEvent::new_event(
+            call_id,
+            "2020-06-01T09:00:00Z".parse().unwrap(),
+            EventKind::Created,
+            "Call from +13473513315".to_owned(),
+        );
+        let second = TimelineEvent::new_event(
+            call_id,
+            "2020-06-01T09:00:00.000001Z".parse().unwrap(),
+            EventKind::Notified,
+            "SMS to +19149543303".to_owned(),
+        );
+        let third = TimelineEvent::new_event(
+            call_id,
+            "2020-06-01T10:00:00Z".parse().unwrap(),
+            EventKind::Acknowledged,
+            "Acknowledged by +19149543303".to_owned(),
+        );
+        assert!(first.event_id.starts_with("2020-06-01T09:00:00.000000Z#"));
+        assert!(first.event_id < second.event_id);
+        assert!(second.event_id < third.event_id);
+
+        let attrs: dynomite::Attributes = third.clone().into();
+        assert_eq!(TimelineEvent::from_attrs(attrs).unwrap(), third);
+    }
+}
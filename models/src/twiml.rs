@@ -0,0 +1,371 @@
+use std::fmt;
+
+/// HTTP method Twilio uses to reach an action URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Post => write!(f, "POST"),
+        }
+    }
+}
+
+/// Records the caller, e.g. to take a voicemail
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Record {
+    pub action: Option<String>,
+    pub method: Option<Method>,
+    pub play_beep: bool,
+    /// Longest recording in seconds
+    pub max_length: Option<u32>,
+    pub transcribe: bool,
+    pub transcribe_callback: Option<String>,
+}
+
+impl Record {
+    pub fn new_record() -> Record {
+        Record::default()
+    }
+
+    /// Where the recording is sent once the caller hangs up or goes quiet
+    pub fn action(mut self, action: &str, method: Method) -> Record {
+        self.action = Some(action.to_string());
+        self.method = Some(method);
+        self
+    }
+
+    pub fn play_beep(mut self) -> Record {
+        self.play_beep = true;
+        self
+    }
+
+    pub fn max_length(mut self, seconds: u32) -> Record {
+        self.max_length = Some(seconds);
+        self
+    }
+
+    /// Has Twilio transcribe the recording and post the text to `callback`
+    pub fn transcribe(mut self, callback: &str) -> Record {
+        self.transcribe = true;
+        self.transcribe_callback = Some(callback.to_string());
+        self
+    }
+}
+
+/// Collects key presses, prompting with the verbs it holds
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Gather {
+    pub num_digits: Option<u32>,
+    pub action: Option<String>,
+    pub method: Option<Method>,
+    /// Seconds to wait for a key before giving up
+    pub timeout: Option<u32>,
+    /// Only `Say`, `Play` and `Pause` may be nested in a gather
+    pub prompt: Vec<Verb>,
+}
+
+impl Gather {
+    pub fn new_gather() -> Gather {
+        Gather::default()
+    }
+
+    pub fn num_digits(mut self, num_digits: u32) -> Gather {
+        self.num_digits = Some(num_digits);
+        self
+    }
+
+    /// Where the keys pressed are sent
+    pub fn action(mut self, action: &str, method: Method) -> Gather {
+        self.action = Some(action.to_string());
+        self.method = Some(method);
+        self
+    }
+
+    pub fn timeout(mut self, seconds: u32) -> Gather {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    pub fn say(mut self, text: &str) -> Gather {
+        self.prompt.push(Verb::Say(text.to_string()));
+        self
+    }
+
+    pub fn play(mut self, url: &str) -> Gather {
+        self.prompt.push(Verb::Play(url.to_string()));
+        self
+    }
+
+    pub fn pause(mut self, seconds: u32) -> Gather {
+        self.prompt.push(Verb::Pause(seconds));
+        self
+    }
+}
+
+/// Connects the caller to one or more numbers. Numbers ring at the same
+/// time and the first to answer is connected.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Dial {
+    /// Seconds to ring before giving up
+    pub timeout: Option<u32>,
+    pub action: Option<String>,
+    pub method: Option<Method>,
+    pub caller_id: Option<String>,
+    pub numbers: Vec<String>,
+}
+
+impl Dial {
+    pub fn new_dial() -> Dial {
+        Dial::default()
+    }
+
+    pub fn timeout(mut self, seconds: u32) -> Dial {
+        self.timeout = Some(seconds);
+        self
+    }
+
+    /// Where the outcome of the dial is sent once it ends
+    pub fn action(mut self, action: &str, method: Method) -> Dial {
+        self.action = Some(action.to_string());
+        self.method = Some(method);
+        self
+    }
+
+    pub fn caller_id(mut self, caller_id: &str) -> Dial {
+        self.caller_id = Some(caller_id.to_string());
+        self
+    }
+
+    pub fn number(mut self, number: &str) -> Dial {
+        self.numbers.push(number.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verb {
+    Say(String),
+    /// Plays the audio at a URL
+    Play(String),
+    /// Silence, in seconds
+    Pause(u32),
+    Record(Record),
+    Gather(Gather),
+    Dial(Dial),
+    Redirect(String, Method),
+    Hangup,
+    /// Replies to an inbound SMS
+    Message(String),
+}
+
+impl fmt::Display for Verb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verb::Say(text) => write!(f, "<Say>{}</Say>", escape_xml(text)),
+            Verb::Play(url) => write!(f, "<Play>{}</Play>", escape_xml(url)),
+            Verb::Pause(length) => write!(f, r#"<Pause length="{}"/>"#, length),
+            Verb::Record(record) => {
+                write!(f, "<Record")?;
+                write_action(f, &record.action, record.method)?;
+                if record.play_beep {
+                    write!(f, r#" playBeep="true""#)?;
+                }
+                write_attr(f, "maxLength", &record.max_length)?;
+                if record.transcribe {
+                    write!(f, r#" transcribe="true""#)?;
+                }
+                write_attr(f, "transcribeCallback", &record.transcribe_callback)?;
+                write!(f, "/>")
+            }
+            Verb::Gather(gather) => {
+                write!(f, "<Gather")?;
+                write_attr(f, "numDigits", &gather.num_digits)?;
+                write_action(f, &gather.action, gather.method)?;
+                write_attr(f, "timeout", &gather.timeout)?;
+                write!(f, ">")?;
+                for verb in &gather.prompt {
+                    write!(f, "{}", verb)?;
+                }
+                write!(f, "</Gather>")
+            }
+            Verb::Dial(dial) => {
+                write!(f, "<Dial")?;
+                write_attr(f, "timeout", &dial.timeout)?;
+                write_action(f, &dial.action, dial.method)?;
+                write_attr(f, "callerId", &dial.caller_id)?;
+                write!(f, ">")?;
+                for number in &dial.numbers {
+                    write!(f, "<Number>{}</Number>", escape_xml(number))?;
+                }
+                write!(f, "</Dial>")
+            }
+            Verb::Redirect(url, method) => write!(
+                f,
+                r#"<Redirect method="{}">{}</Redirect>"#,
+                method,
+                escape_xml(url)
+            ),
+            Verb::Hangup => write!(f, "<Hangup/>"),
+            Verb::Message(text) => write!(f, "<Message>{}</Message>", escape_xml(text)),
+        }
+    }
+}
+
+fn write_attr<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    value: &Option<T>,
+) -> fmt::Result {
+    match value {
+        Some(value) => write!(f, r#" {}="{}""#, name, escape_xml(&value.to_string())),
+        None => Ok(()),
+    }
+}
+
+fn write_action(
+    f: &mut fmt::Formatter<'_>,
+    action: &Option<String>,
+    method: Option<Method>,
+) -> fmt::Result {
+    write_attr(f, "action", action)?;
+    write_attr(f, "method", &method)
+}
+
+/// A TwiML document, built up verb by verb in the order Twilio runs them
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TwimlResponse {
+    pub verbs: Vec<Verb>,
+}
+
+impl TwimlResponse {
+    pub fn new_response() -> TwimlResponse {
+        TwimlResponse::default()
+    }
+
+    pub fn verb(mut self, verb: Verb) -> TwimlResponse {
+        self.verbs.push(verb);
+        self
+    }
+
+    pub fn say(self, text: &str) -> TwimlResponse {
+        self.verb(Verb::Say(text.to_string()))
+    }
+
+    pub fn play(self, url: &str) -> TwimlResponse {
+        self.verb(Verb::Play(url.to_string()))
+    }
+
+    pub fn pause(self, seconds: u32) -> TwimlResponse {
+        self.verb(Verb::Pause(seconds))
+    }
+
+    pub fn record(self, record: Record) -> TwimlResponse {
+        self.verb(Verb::Record(record))
+    }
+
+    pub fn gather(self, gather: Gather) -> TwimlResponse {
+        self.verb(Verb::Gather(gather))
+    }
+
+    pub fn dial(self, dial: Dial) -> TwimlResponse {
+        self.verb(Verb::Dial(dial))
+    }
+
+    pub fn redirect(self, url: &str, method: Method) -> TwimlResponse {
+        self.verb(Verb::Redirect(url.to_string(), method))
+    }
+
+    pub fn hangup(self) -> TwimlResponse {
+        self.verb(Verb::Hangup)
+    }
+
+    pub fn message(self, text: &str) -> TwimlResponse {
+        self.verb(Verb::Message(text.to_string()))
+    }
+}
+
+impl fmt::Display for TwimlResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, r#"<?xml version="1.0" encoding="UTF-8"?><Response>"#)?;
+        for verb in &self.verbs {
+            write!(f, "{}", verb)?;
+        }
+        write!(f, "</Response>")
+    }
+}
+
+/// Escapes text for use in XML content and attribute values
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_twiml() {
+        assert_eq!(
+            TwimlResponse::new_response().to_string(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><Response></Response>"#
+        );
+        let twiml = TwimlResponse::new_response()
+            .gather(
+                Gather::new_gather()
+                    .num_digits(1)
+                    .action("?call_id=1&option=2", Method::Post)
+                    .timeout(5)
+                    .say("Press 1 for <billing> & \"sales\"")
+                    .pause(1)
+                    .play("https://example.com/hold.mp3"),
+            )
+            .record(
+                Record::new_record()
+                    .action("?call_id=1", Method::Post)
+                    .play_beep()
+                    .max_length(120)
+                    .transcribe("https://example.com/receive_transcription?call_id=1"),
+            )
+            .dial(
+                Dial::new_dial()
+                    .timeout(20)
+                    .action("?dial=1", Method::Get)
+                    .caller_id("+12183957949")
+                    .number("+19149543303")
+                    .number("+19147251309"),
+            )
+            .redirect("https://example.com/page_call?level=0", Method::Post)
+            .hangup()
+            .to_string();
+        assert_eq!(
+            twiml,
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><Response>"#,
+                r#"<Gather numDigits="1" action="?call_id=1&amp;option=2" method="POST" timeout="5">"#,
+                r#"<Say>Press 1 for &lt;billing&gt; &amp; &quot;sales&quot;</Say>"#,
+                r#"<Pause length="1"/><Play>https://example.com/hold.mp3</Play></Gather>"#,
+                r#"<Record action="?call_id=1" method="POST" playBeep="true" maxLength="120" transcribe="true" transcribeCallback="https://example.com/receive_transcription?call_id=1"/>"#,
+                r#"<Dial timeout="20" action="?dial=1" method="GET" callerId="+12183957949">"#,
+                r#"<Number>+19149543303</Number><Number>+19147251309</Number></Dial>"#,
+                r#"<Redirect method="POST">https://example.com/page_call?level=0</Redirect>"#,
+                r#"<Hangup/></Response>"#
+            )
+        );
+        assert_eq!(
+            TwimlResponse::new_response()
+                .message("Call 4821 & co")
+                .to_string(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><Response><Message>Call 4821 &amp; co</Message></Response>"#
+        );
+    }
+}
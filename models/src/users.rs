@@ -1,12 +1,135 @@
+use crate::channel::ChannelKind;
+use crate::validate::{check_not_blank, check_phone_number, ValidationError};
 use dynomite::{dynamodb::AttributeValue, error::AttributeError, Attribute};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Attribute)]
+pub enum ContactKind {
+    /// Reached by SMS and voice calls
+    Phone,
+    Email,
+    Webhook,
+}
+
+impl ContactKind {
+    /// The kind of contact a channel delivers to
+    pub fn for_channel(channel: ChannelKind) -> ContactKind {
+        match channel {
+            ChannelKind::Sms | ChannelKind::Voice => ContactKind::Phone,
+            ChannelKind::Email => ContactKind::Email,
+            ChannelKind::Webhook => ContactKind::Webhook,
+        }
+    }
+}
+
+/// A way of reaching a user besides their primary number
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContactMethod {
+    pub kind: ContactKind,
+    /// A phone number, email address or URL
+    pub address: String,
+    /// e.g. "work cell"
+    pub label: String,
+}
+
+impl Attribute for ContactMethod {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("kind".to_string(), self.kind.into_attr());
+        map.insert("address".to_string(), self.address.into_attr());
+        map.insert("label".to_string(), self.label.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(ContactMethod {
+                    kind: ContactKind::from_attr(field("kind")?)?,
+                    address: String::from_attr(field("address")?)?,
+                    label: String::from_attr(field("label")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// "Page me over `channel`, `after` seconds into an escalation step"
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotificationRule {
+    pub channel: ChannelKind,
+    pub after: i64,
+}
+
+impl Attribute for NotificationRule {
+    fn into_attr(self) -> AttributeValue {
+        let mut map = HashMap::new();
+        map.insert("channel".to_string(), self.channel.into_attr());
+        map.insert("after".to_string(), self.after.into_attr());
+        AttributeValue {
+            m: Some(map),
+            ..AttributeValue::default()
+        }
+    }
+
+    fn from_attr(value: AttributeValue) -> Result<Self, AttributeError> {
+        match value.m {
+            Some(mut m) => {
+                let mut field = |name: &str| {
+                    m.remove(name).ok_or(AttributeError::MissingField {
+                        name: name.to_string(),
+                    })
+                };
+                Ok(NotificationRule {
+                    channel: ChannelKind::from_attr(field("channel")?)?,
+                    after: i64::from_attr(field("after")?)?,
+                })
+            }
+            None => Err(AttributeError::InvalidType),
+        }
+    }
+}
+
+/// Rules for users who haven't set their own: every channel straight away
+const DEFAULT_RULES: [NotificationRule; 4] = [
+    NotificationRule {
+        channel: ChannelKind::Sms,
+        after: 0,
+    },
+    NotificationRule {
+        channel: ChannelKind::Voice,
+        after: 0,
+    },
+    NotificationRule {
+        channel: ChannelKind::Email,
+        after: 0,
+    },
+    NotificationRule {
+        channel: ChannelKind::Webhook,
+        after: 0,
+    },
+];
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct User {
     uuid: String,
     group_id: String,
     name: String,
     pub number: String,
+    #[serde(default)]
+    contacts: Vec<ContactMethod>,
+    #[serde(default)]
+    rules: Vec<NotificationRule>,
 }
 impl User {
     pub fn new_user(uuid: String, group_id: String, name: String, number: String) -> User {
@@ -15,7 +138,130 @@ impl User {
             group_id,
             name,
             number,
+            contacts: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    pub fn group_id(&self) -> &str {
+        &self.group_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks the user can actually be paged before they are stored
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        check_not_blank("uuid", &self.uuid)?;
+        check_not_blank("name", &self.name)?;
+        check_phone_number("number", &self.number)?;
+        for (i, contact) in self.contacts.iter().enumerate() {
+            let field = format!("contacts[{}].address", i);
+            match contact.kind {
+                ContactKind::Phone => check_phone_number(&field, &contact.address)?,
+                ContactKind::Email => {
+                    let mut parts = contact.address.splitn(2, '@');
+                    let valid = match (parts.next(), parts.next()) {
+                        (Some(local), Some(domain)) => {
+                            !local.is_empty() && domain.contains('.') && !domain.contains('@')
+                        }
+                        _ => false,
+                    };
+                    if !valid || contact.address.contains(char::is_whitespace) {
+                        return Err(ValidationError::new(&field, "must be an email address"));
+                    }
+                }
+                ContactKind::Webhook => {
+                    if !contact.address.starts_with("https://") {
+                        return Err(ValidationError::new(&field, "must be an https:// URL"));
+                    }
+                }
+            }
         }
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.after < 0 {
+                return Err(ValidationError::new(
+                    &format!("rules[{}].after", i),
+                    "must not be negative",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contacts(&self) -> &[ContactMethod] {
+        &self.contacts
+    }
+
+    pub fn add_contact(&mut self, kind: ContactKind, address: String, label: String) {
+        self.contacts.push(ContactMethod {
+            kind,
+            address,
+            label,
+        });
+    }
+
+    /// The user's own rules, or paging on every channel at once if they have none
+    pub fn rules(&self) -> &[NotificationRule] {
+        if self.rules.is_empty() {
+            &DEFAULT_RULES
+        } else {
+            &self.rules
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<NotificationRule>) {
+        self.rules = rules;
+    }
+
+    /// Everywhere the user is reached over `channel`, primary number first
+    pub fn addresses(&self, channel: ChannelKind) -> Vec<&str> {
+        let kind = ContactKind::for_channel(channel);
+        let primary = match kind {
+            ContactKind::Phone => Some(self.number.as_str()),
+            _ => None,
+        };
+        primary
+            .into_iter()
+            .chain(
+                self.contacts
+                    .iter()
+                    .filter(|contact| contact.kind == kind)
+                    .map(|contact| contact.address.as_str()),
+            )
+            .collect()
+    }
+
+    /// Whether `number` is one of the user's phones, e.g. to accept an SMS reply from it
+    pub fn has_phone(&self, number: &str) -> bool {
+        self.addresses(ChannelKind::Sms).contains(&number)
+    }
+
+    /// Channels the user is paged on `after` seconds into a step
+    pub fn channels_due(&self, after: i64) -> Vec<ChannelKind> {
+        self.rules()
+            .iter()
+            .filter(|rule| rule.after == after)
+            .map(|rule| rule.channel)
+            .collect()
+    }
+
+    /// Every later point in a step at which the user has something due
+    pub fn later_rules(&self) -> Vec<i64> {
+        let mut delays: Vec<i64> = self
+            .rules()
+            .iter()
+            .map(|rule| rule.after)
+            .filter(|after| *after > 0)
+            .collect();
+        delays.sort_unstable();
+        delays.dedup();
+        delays
     }
 }
 impl Attribute for User {
@@ -25,6 +271,8 @@ impl Attribute for User {
         map.insert("group_id".to_string(), self.group_id.into_attr());
         map.insert("name".to_string(), self.name.into_attr());
         map.insert("number".to_string(), self.number.into_attr());
+        map.insert("contacts".to_string(), self.contacts.into_attr());
+        map.insert("rules".to_string(), self.rules.into_attr());
         AttributeValue {
             m: Some(map),
             ..AttributeValue::default()
@@ -38,8 +286,117 @@ impl Attribute for User {
                 group_id: String::from_attr(m.get(&"group_id".to_string()).unwrap().clone())?,
                 name: String::from_attr(m.get(&"name".to_string()).unwrap().clone())?,
                 number: String::from_attr(m.get(&"number".to_string()).unwrap().clone())?,
+                // Users stored before contact methods and rules existed have neither
+                contacts: match m.get("contacts") {
+                    Some(contacts) => Vec::from_attr(contacts.clone())?,
+                    None => Vec::new(),
+                },
+                rules: match m.get("rules") {
+                    Some(rules) => Vec::from_attr(rules.clone())?,
+                    None => Vec::new(),
+                },
             }),
             None => Err(AttributeError::InvalidType),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contacts_and_rules() {
+        let mut user = User::new_user(
+            "1".to_owned(),
+            "+12183957949".to_owned(),
+            "Tobias Funke".to_owned(),
+            "+19149543303".to_owned(),
+        );
+        assert_eq!(user.addresses(ChannelKind::Voice), vec!["+19149543303"]);
+        assert!(user.addresses(ChannelKind::Email).is_empty());
+        assert_eq!(user.channels_due(0).len(), 4);
+        assert!(user.later_rules().is_empty());
+
+        user.add_contact(
+            ContactKind::Phone,
+            "+19147251309".to_owned(),
+            "home".to_owned(),
+        );
+        user.add_contact(
+            ContactKind::Email,
+            "tobias@example.com".to_owned(),
+            "work".to_owned(),
+        );
+        user.set_rules(vec![
+            NotificationRule {
+                channel: ChannelKind::Sms,
+                after: 0,
+            },
+            NotificationRule {
+                channel: ChannelKind::Voice,
+                after: 120,
+            },
+            NotificationRule {
+                channel: ChannelKind::Email,
+                after: 300,
+            },
+        ]);
+        assert_eq!(
+            user.addresses(ChannelKind::Sms),
+            vec!["+19149543303", "+19147251309"]
+        );
+        assert_eq!(
+            user.addresses(ChannelKind::Email),
+            vec!["tobias@example.com"]
+        );
+        assert!(user.has_phone("+19147251309"));
+        assert!(!user.has_phone("tobias@example.com"));
+        assert_eq!(user.channels_due(0), vec![ChannelKind::Sms]);
+        assert_eq!(user.channels_due(120), vec![ChannelKind::Voice]);
+        assert_eq!(user.later_rules(), vec![120, 300]);
+
+        assert_eq!(user.validate(), Ok(()));
+        assert_eq!(User::from_attr(user.clone().into_attr()).unwrap(), user);
+
+        let mut attr = user.clone().into_attr();
+        let map = attr.m.as_mut().unwrap();
+        map.remove("contacts");
+        map.remove("rules");
+        let stored = User::from_attr(attr).unwrap();
+        assert!(stored.contacts().is_empty());
+        assert_eq!(stored.rules(), &DEFAULT_RULES[..]);
+    }
+    #[test]
+    fn test_validate() {
+        let user = User::new_user(
+            "1".to_owned(),
+            "acme-support".to_owned(),
+            "Tobias Funke".to_owned(),
+            "+19149543303".to_owned(),
+        );
+        let field = |user: &User| user.validate().unwrap_err().field;
+
+        let mut bad = user.clone();
+        bad.name = "".to_owned();
+        assert_eq!(field(&bad), "name");
+
+        for (kind, address) in &[
+            (ContactKind::Phone, "914-954-3303"),
+            (ContactKind::Email, "tobias@"),
+            (ContactKind::Email, "tobias@example"),
+            (ContactKind::Webhook, "http://example.com/hook"),
+        ] {
+            let mut bad = user.clone();
+            bad.add_contact(*kind, address.to_string(), "work".to_owned());
+            assert_eq!(field(&bad), "contacts[0].address");
+        }
+
+        let mut bad = user;
+        bad.set_rules(vec![NotificationRule {
+            channel: ChannelKind::Sms,
+            after: -60,
+        }]);
+        assert_eq!(field(&bad), "rules[0].after");
+    }
+}
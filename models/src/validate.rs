@@ -0,0 +1,66 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Why a group or user sent to the API was refused
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub field: String,
+    pub reason: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &str, reason: &str) -> ValidationError {
+        ValidationError {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+
+/// Numbers in E.164 form, e.g. "+12183957949", as Twilio sends them
+pub fn check_phone_number(field: &str, number: &str) -> Result<(), ValidationError> {
+    let digits = match number.chars().next() {
+        Some('+') => &number[1..],
+        _ => "",
+    };
+    if (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(ValidationError::new(
+            field,
+            "must be a phone number like +12183957949",
+        ))
+    }
+}
+
+pub fn check_not_blank(field: &str, value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        Err(ValidationError::new(field, "must not be blank"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        assert_eq!(check_phone_number("number", "+12183957949"), Ok(()));
+        assert!(check_phone_number("number", "12183957949").is_err());
+        assert!(check_phone_number("number", "+1218395").is_err());
+        assert!(check_phone_number("number", "+1218-395-7949").is_err());
+        assert_eq!(
+            check_not_blank("name", "  ").unwrap_err().to_string(),
+            "name: must not be blank"
+        );
+        assert_eq!(check_not_blank("name", "Tobias"), Ok(()));
+    }
+}
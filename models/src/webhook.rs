@@ -0,0 +1,208 @@
+use hmac::{Hmac, Mac};
+use lambda_http::{Body, Response};
+use log::warn;
+use serde_json::{Map, Value};
+use sha1::Sha1;
+
+/// Header Twilio sends the signature of each webhook request in
+pub const SIGNATURE_HEADER: &str = "X-Twilio-Signature";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WebhookError {
+    /// The body could not be read as form parameters
+    Malformed(String),
+    /// A parameter the handler needs was not sent
+    MissingField(String),
+    /// No signature, or one that does not match the request
+    InvalidSignature,
+}
+
+impl WebhookError {
+    /// HTTP status to reject the request with
+    pub fn status(&self) -> u16 {
+        match self {
+            WebhookError::Malformed(_) | WebhookError::MissingField(_) => 400,
+            WebhookError::InvalidSignature => 403,
+        }
+    }
+}
+
+/// Turns away a request that isn't a well-formed webhook from Twilio
+pub fn reject(e: WebhookError) -> Response<Body> {
+    warn!("Rejected request: {:?}", e);
+    Response::builder()
+        .status(e.status())
+        .body(Body::from(format!("{:?}", e)))
+        .unwrap()
+}
+
+/// URL for Twilio to call back with `params` as its query. Twilio signs the
+/// URL exactly as it was given, while handlers only see the query as an
+/// unordered map, so parameters always go in sorted order for handlers to
+/// rebuild the same URL. Values are not escaped: callbacks only carry ids
+/// and digits.
+pub fn callback_url<K: AsRef<str>, V: AsRef<str>>(base: &str, params: &[(K, V)]) -> String {
+    let mut params: Vec<(&str, &str)> = params
+        .iter()
+        .map(|(name, value)| (name.as_ref(), value.as_ref()))
+        .collect();
+    params.sort();
+    match params.len() {
+        0 => base.to_string(),
+        _ => format!(
+            "{}?{}",
+            base,
+            params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&")
+        ),
+    }
+}
+
+/// Twilio's signature for a request to `url` with form `params`: the URL
+/// followed by each parameter's name and value in sorted order, signed with
+/// HMAC-SHA1 under the account's auth token and base64 encoded
+pub fn signature(auth_token: &str, url: &str, params: &[(String, String)]) -> String {
+    base64::encode(mac(auth_token, url, params).result().code())
+}
+
+fn mac(auth_token: &str, url: &str, params: &[(String, String)]) -> Hmac<Sha1> {
+    let mut params: Vec<&(String, String)> = params.iter().collect();
+    params.sort();
+    let mut mac = Hmac::<Sha1>::new_varkey(auth_token.as_bytes()).expect("HMAC takes any key");
+    mac.input(url.as_bytes());
+    for (name, value) in params {
+        mac.input(name.as_bytes());
+        mac.input(value.as_bytes());
+    }
+    mac
+}
+
+/// Checks that a webhook request really came from Twilio and returns its
+/// form body. `base_url` is the public URL the handler was given to Twilio
+/// as, and `query` the request's query parameters.
+pub fn verify_webhook<'a>(
+    auth_token: &str,
+    base_url: &str,
+    query: impl IntoIterator<Item = (&'a str, &'a str)>,
+    body: &str,
+    signature: Option<&str>,
+) -> Result<Value, WebhookError> {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(body).map_err(|e| WebhookError::Malformed(e.to_string()))?;
+    let query: Vec<(&str, &str)> = query.into_iter().collect();
+    let url = callback_url(base_url, &query);
+    let signature = signature
+        .and_then(|signature| base64::decode(signature).ok())
+        .ok_or(WebhookError::InvalidSignature)?;
+    // Compared in constant time so the signature can't be guessed byte by byte
+    mac(auth_token, &url, &params)
+        .verify(&signature)
+        .map_err(|_e| WebhookError::InvalidSignature)?;
+    Ok(Value::Object(
+        params
+            .into_iter()
+            .map(|(name, value)| (name, Value::String(value)))
+            .collect::<Map<_, _>>(),
+    ))
+}
+
+/// Reads a parameter a handler can't do without from a webhook body
+pub fn required<'a>(body: &'a Value, name: &str) -> Result<&'a str, WebhookError> {
+    body[name]
+        .as_str()
+        .ok_or_else(|| WebhookError::MissingField(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_params() -> Vec<(String, String)> {
+        vec![
+            ("CallSid", "CA1234567890ABCDE"),
+            ("Caller", "+12349013030"),
+            ("Digits", "1234"),
+            ("From", "+12349013030"),
+            ("To", "+18005551212"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_signature() {
+        // Example from Twilio's security documentation
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        let mut params = test_params();
+        assert_eq!(
+            signature("12345", url, &params),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        );
+        params.reverse();
+        assert_eq!(
+            signature("12345", url, &params),
+            "0/KCTR6DLpKmkAf8muzZqo1nDgQ="
+        );
+    }
+
+    #[test]
+    fn test_verify_webhook() {
+        assert_eq!(
+            callback_url(
+                "https://example.com/receive_call",
+                &[("option", "2"), ("call_id", "abc")]
+            ),
+            "https://example.com/receive_call?call_id=abc&option=2"
+        );
+        assert_eq!(
+            callback_url::<&str, &str>("https://example.com/receive_call", &[]),
+            "https://example.com/receive_call"
+        );
+
+        let base = "https://example.com/receive_call";
+        let url = callback_url(base, &[("call_id", "abc"), ("option", "2")]);
+        let body = serde_urlencoded::to_string(test_params()).unwrap();
+        let signed = signature("12345", &url, &test_params());
+        // Query maps come back in any order
+        let query = vec![("option", "2"), ("call_id", "abc")];
+
+        let verified = verify_webhook("12345", base, query.clone(), &body, Some(&signed)).unwrap();
+        assert_eq!(required(&verified, "To"), Ok("+18005551212"));
+        assert_eq!(
+            required(&verified, "RecordingUrl"),
+            Err(WebhookError::MissingField("RecordingUrl".to_string()))
+        );
+
+        let rejected = verify_webhook("12345", base, query.clone(), &body, None);
+        assert_eq!(rejected, Err(WebhookError::InvalidSignature));
+        assert_eq!(rejected.unwrap_err().status(), 403);
+        let tampered = body.replace("1234", "9");
+        assert_eq!(
+            verify_webhook("12345", base, query.clone(), &tampered, Some(&signed)),
+            Err(WebhookError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_webhook("54321", base, query.clone(), &body, Some(&signed)),
+            Err(WebhookError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_webhook(
+                "12345",
+                base,
+                vec![("call_id", "abc")],
+                &body,
+                Some(&signed)
+            ),
+            Err(WebhookError::InvalidSignature)
+        );
+        assert_eq!(WebhookError::MissingField("To".to_string()).status(), 400);
+        assert_eq!(
+            reject(WebhookError::MissingField("To".to_string())).status(),
+            400
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::Utc;
+use lambda_http::{
+    http::header::{HeaderValue, CONTENT_TYPE},
+    lambda, Body,
+    Body::Text,
+    IntoResponse, Request, RequestExt, Response,
+};
+use lambda_runtime::{error::HandlerError, Context};
+use log::Level::Info;
+use models::{
+    call::{Call, ReadError, UpdateError},
+    escalation::EscalationPolicy,
+    notify::{page_twiml, page_url, play_message_twiml, say_twiml, PageKey},
+    timeline::{EventKind, TimelineEvent},
+    webhook::{reject, verify_webhook, WebhookError, SIGNATURE_HEADER},
+};
+use rusoto_core::Region;
+use rusoto_sqs::SqsClient;
+use simple_logger::init_with_level;
+use std::env;
+
+fn main() {
+    init_with_level(Info).unwrap();
+    lambda!(handler);
+}
+
+/// Serves the TwiML Twilio plays when a paged user answers the call, and
+/// handles the key they press in response
+#[tokio::main]
+async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
+    let query = request.query_string_parameters();
+    let request_body = match verify_webhook(
+        &env::var("TWILIO_TOKEN")?,
+        &env::var("PAGE_CALL_URL")?,
+        query.iter(),
+        match request.body() {
+            Text(string) => string.as_ref(),
+            _ => "",
+        },
+        request
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok()),
+    ) {
+        Ok(request_body) => request_body,
+        Err(e) => return Ok(reject(e)),
+    };
+    let call_id = match query.get("call_id") {
+        Some(call_id) => call_id.to_string(),
+        None => return Ok(reject(WebhookError::MissingField("call_id".to_string()))),
+    };
+    let level: u32 = query
+        .get("level")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(0);
+    let call_table = env::var("CALL_TABLE")?;
+    let call = Call::get_call(call_table.clone(), Region::UsEast1, call_id)
+        .await
+        .map_err(|e| match e {
+            ReadError::NotFound => HandlerError::from("Call Not Found"),
+            _ => HandlerError::from("CallReadFail"),
+        })?;
+    let page_url = page_url(&env::var("PAGE_CALL_URL")?, &call, level);
+
+    let twiml = match request_body["Digits"].as_str().and_then(PageKey::parse) {
+        None => page_twiml(&call, &page_url),
+        Some(PageKey::PlayMessage) => play_message_twiml(&call, &page_url),
+        Some(_) if !call.state.is_open() => {
+            say_twiml("This page has already been handled. Goodbye.")
+        }
+        Some(PageKey::Acknowledge) => {
+            // On an outbound page call, To is the paged user's number
+            let by = request_body["To"].as_str().unwrap_or_default().to_string();
+            match Call::update_call(
+                call_table,
+                Region::UsEast1,
+                call.call_id.to_string(),
+                |call| call.acknowledge(by.clone(), Utc::now()),
+            )
+            .await
+            {
+                Ok(_) => {
+                    TimelineEvent::append(
+                        env::var("TIMELINE_TABLE")?,
+                        Region::UsEast1,
+                        call.call_id,
+                        EventKind::Acknowledged,
+                        format!("Acknowledged by {} on a page call", by),
+                    )
+                    .await;
+                    say_twiml("Page acknowledged. Goodbye.")
+                }
+                // Acknowledged or resolved by someone else since the call was read
+                Err(UpdateError::Rejected(_)) => {
+                    say_twiml("This page has already been handled. Goodbye.")
+                }
+                Err(_) => return Err(HandlerError::from("CallWriteFail")),
+            }
+        }
+        Some(PageKey::Escalate) => {
+            let policy = EscalationPolicy::get_escalation_policy(
+                env::var("ESCALATION_TABLE")?,
+                Region::UsEast1,
+                call.route().to_string(),
+            )
+            .await
+            .unwrap_or_else(|| EscalationPolicy::default_policy(call.route().to_string()));
+            match policy.next_level(level) {
+                Some(next_level) => {
+                    let update = Call::update_call(
+                        call_table,
+                        Region::UsEast1,
+                        call.call_id.to_string(),
+                        |call| {
+                            if !call.state.is_open() {
+                                return Err(());
+                            }
+                            call.level = next_level;
+                            call.repeat = 0;
+                            Ok(())
+                        },
+                    )
+                    .await;
+                    let call = match update {
+                        Ok((call, ())) => call,
+                        Err(UpdateError::Rejected(())) => {
+                            return Ok(twiml_response(say_twiml(
+                                "This page has already been handled. Goodbye.",
+                            )))
+                        }
+                        Err(_) => return Err(HandlerError::from("CallWriteFail")),
+                    };
+                    // Carries the same attempt as the delayed message already
+                    // queued, so whichever is delivered second is dropped
+                    let sqs_client = SqsClient::new(Region::UsEast1);
+                    call.sqs_push(&sqs_client, env::var("ESCALATION_QUEUE_URL")?, 0)
+                        .await
+                        .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+                    TimelineEvent::append(
+                        env::var("TIMELINE_TABLE")?,
+                        Region::UsEast1,
+                        call.call_id,
+                        EventKind::Escalated,
+                        format!(
+                            "Escalated to level {} by {} on a page call",
+                            next_level,
+                            request_body["To"].as_str().unwrap_or_default()
+                        ),
+                    )
+                    .await;
+                    say_twiml("Escalating to the next level. Goodbye.")
+                }
+                None => say_twiml("There is no further escalation level. Goodbye."),
+            }
+        }
+    };
+
+    Ok(twiml_response(twiml))
+}
+
+fn twiml_response(twiml: String) -> Response<Body> {
+    let mut twiml = twiml.into_response();
+    twiml.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str("application/xml").unwrap(),
+    );
+    twiml
+}
@@ -1,24 +1,29 @@
 use chrono::Utc;
 use dynomite::dynamodb::DynamoDbClient;
-use futures::{try_join, TryFutureExt};
 use lambda_http::{
-    http::{
-        header::{HeaderValue, CONTENT_TYPE},
-        method::Method,
-    },
+    http::header::{HeaderValue, CONTENT_TYPE},
     lambda, Body,
     Body::Text,
-    IntoResponse, Request, Response,
+    IntoResponse, Request, RequestExt, Response,
 };
 use lambda_runtime::{error::HandlerError, Context};
 use log::Level::Info;
-use models::{call::Call, schedule::Schedule, users::User};
+use models::{
+    call::{Call, Caller, FollowUp},
+    escalation::EscalationPolicy,
+    forward::{dial_answered, hangup_twiml},
+    group::{group_key, Group},
+    ivr::{call_action, record_twiml, IvrMenu},
+    queue::EscalationMessage,
+    schedule::Schedule,
+    timeline::{EventKind, TimelineEvent},
+    webhook::{reject, required, verify_webhook, SIGNATURE_HEADER},
+};
 use rusoto_core::{Region, RusotoError::Service};
 use rusoto_sqs::{
     SendMessageError::{InvalidMessageContents, UnsupportedOperation},
     SqsClient,
 };
-use serde_json::Value;
 use simple_logger::init_with_level;
 use std::env;
 use uuid::Uuid;
@@ -28,54 +33,275 @@ fn main() {
     lambda!(handler);
 }
 
+/// Adds a repeat call to the caller's open incident instead of starting
+/// another escalation
+async fn attach_follow_up(
+    incident: Call,
+    call_id: Uuid,
+    message_url: String,
+    caller: Caller,
+    call_table: String,
+    timeline_table: String,
+    queue_url: String,
+) -> Result<Response<Body>, HandlerError> {
+    let follow_up = FollowUp {
+        call_id: Some(call_id),
+        message_url,
+        recording_key: None,
+        transcription: None,
+        caller,
+    };
+    let (incident, ()) = Call::update_call(
+        call_table,
+        Region::UsEast1,
+        incident.call_id.to_string(),
+        |call| -> Result<(), ()> {
+            call.follow_ups.push(follow_up.clone());
+            Ok(())
+        },
+    )
+    .await
+    .map_err(|_e| HandlerError::from("CallWriteFail"))?;
+    EscalationMessage::new_archive(&incident, Utc::now())
+        .send(&SqsClient::new(Region::UsEast1), queue_url)
+        .await
+        .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+    TimelineEvent::append(
+        timeline_table,
+        Region::UsEast1,
+        incident.call_id,
+        EventKind::RepeatCall,
+        format!(
+            "Repeat call from {} joined the incident",
+            follow_up.caller.describe(&incident.phone_number)
+        ),
+    )
+    .await;
+    Ok("Success!".into_response())
+}
+
+/// TwiML for a caller whose route is known: rings whoever is on call if
+/// the route forwards calls, and takes a message once nobody is left to
+/// try. `next_dial` is how many dial attempts have already gone unanswered.
+/// `greeting` is said to callers who go straight to leaving a message.
+async fn route_twiml(
+    call_id: &Uuid,
+    route: &str,
+    option: Option<&str>,
+    next_dial: usize,
+    greeting: Option<&str>,
+    transcription_url: &str,
+) -> Result<String, HandlerError> {
+    let forwarding = EscalationPolicy::get_escalation_policy(
+        env::var("ESCALATION_TABLE")?,
+        Region::UsEast1,
+        route.to_string(),
+    )
+    .await
+    .and_then(|policy| policy.forwarding().cloned());
+    if let Some(forwarding) = forwarding {
+        let numbers: Vec<String> = Schedule::get_schedule(
+            env::var("GROUP_TABLE")?,
+            Region::UsEast1,
+            route.to_string(),
+            "group_id".to_string(),
+        )
+        .await
+        .and_then(|schedule| schedule.get_providers(Utc::now().into()))
+        .unwrap_or_else(Vec::new)
+        .into_iter()
+        .map(|user| user.number)
+        .collect();
+        if let Some(twiml) = forwarding.dial_twiml(&numbers, next_dial, call_id, option) {
+            return Ok(twiml);
+        }
+    }
+    // Callers who were put through to no one have already been greeted
+    let greeting = greeting.filter(|_| next_dial == 0);
+    Ok(record_twiml(call_id, option, greeting, transcription_url))
+}
+
+/// Twilio webhook for calls to a group number. Twilio posts here when the
+/// call comes in and after each menu choice or forwarded dial, which are
+/// answered with TwiML, and finally with the recording of the message left.
 #[tokio::main]
 async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
-    let request_body: Value = serde_urlencoded::from_str(match request.body() {
-        Text(string) => string.as_ref(),
-        _ => "",
-    })
-    .unwrap();
-    match *request.method() {
-        Method::GET => {
-            let mut twiml = r#"<?xml version="1.0" encoding="UTF-8"?> <Response> <Say> Please leave a message at the beep </Say> <Record playBeep="true" /> </Response>"#.into_response();
+    let query = request.query_string_parameters();
+    let request_body = match verify_webhook(
+        &env::var("TWILIO_TOKEN")?,
+        &env::var("RECEIVE_CALL_URL")?,
+        query.iter(),
+        match request.body() {
+            Text(string) => string.as_ref(),
+            _ => "",
+        },
+        request
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok()),
+    ) {
+        Ok(request_body) => request_body,
+        Err(e) => return Ok(reject(e)),
+    };
+    let group_number = match required(&request_body, "To") {
+        Ok(group_number) => group_number.to_string(),
+        Err(e) => return Ok(reject(e)),
+    };
+    let group = Group::get_group_by_number(
+        env::var("GROUPS_TABLE")?,
+        env::var("NUMBER_TABLE")?,
+        &DynamoDbClient::new(Region::UsEast1),
+        group_number.clone(),
+    )
+    .await;
+    let group_id = group_key(group.as_ref(), &group_number);
+    match request_body["RecordingUrl"].as_str() {
+        None => {
+            // The id is chosen now so the transcription callback can find the call
+            let call_id = query
+                .get("call_id")
+                .and_then(|call_id| Uuid::parse_str(call_id).ok())
+                .unwrap_or_else(Uuid::new_v4);
+            let transcription_url = env::var("TRANSCRIPTION_URL")?;
+            let menu =
+                IvrMenu::get_ivr_menu(env::var("IVR_TABLE")?, Region::UsEast1, group_id.clone())
+                    .await;
+            // Dial actions carry the option already chosen; menus send Digits
+            let chosen = query
+                .get("option")
+                .or_else(|| request_body["Digits"].as_str());
+            let next_dial = query
+                .get("dial")
+                .and_then(|dial| dial.parse().ok())
+                .unwrap_or(0);
+            let answered = match request_body["DialCallStatus"].as_str() {
+                Some(status) => dial_answered(status),
+                None => false,
+            };
+            let twiml = if answered {
+                hangup_twiml()
+            } else {
+                match menu {
+                    Some(menu) => match chosen.and_then(|digit| menu.option(digit)) {
+                        Some(option) => {
+                            route_twiml(
+                                &call_id,
+                                &option.route,
+                                Some(&option.digit),
+                                next_dial,
+                                None,
+                                &transcription_url,
+                            )
+                            .await?
+                        }
+                        // Play the menu again after a key that isn't on it
+                        None => menu.menu_twiml(
+                            &call_action(&call_id, None, &[]),
+                            &call_id,
+                            &transcription_url,
+                        ),
+                    },
+                    None => {
+                        let greeting = group.as_ref().and_then(|group| group.greeting.as_deref());
+                        route_twiml(
+                            &call_id,
+                            &group_id,
+                            None,
+                            next_dial,
+                            greeting,
+                            &transcription_url,
+                        )
+                        .await?
+                    }
+                }
+            };
+            let mut twiml = twiml.into_response();
             twiml.headers_mut().insert(
                 CONTENT_TYPE,
                 HeaderValue::from_str("application/xml").unwrap(),
             );
             Ok(twiml)
         }
-        Method::POST => {
-            let phone_number: String = request_body["To"].as_str().unwrap().to_string();
+        Some(message_url) => {
+            let message_url = message_url.to_string();
+            let phone_number = match required(&request_body, "From") {
+                Ok(phone_number) => phone_number.to_string(),
+                Err(e) => return Ok(reject(e)),
+            };
             let call_table = env::var("CALL_TABLE")?;
-            let group_table = env::var("GROUP_TABLE")?;
+            let queue_url = env::var("ESCALATION_QUEUE_URL")?;
+            let timeline_table = env::var("TIMELINE_TABLE")?;
+
+            let received_at = Utc::now();
+            let call_id = query
+                .get("call_id")
+                .and_then(|call_id| Uuid::parse_str(call_id).ok())
+                .unwrap_or_else(Uuid::new_v4);
+            let caller = Caller::from_webhook(&request_body, received_at);
 
-            let users: Vec<User> = Schedule::get_schedule(
-                group_table,
+            let policy = EscalationPolicy::get_escalation_policy(
+                env::var("ESCALATION_TABLE")?,
                 Region::UsEast1,
+                group_id.clone(),
+            )
+            .await
+            .unwrap_or_else(|| EscalationPolicy::default_policy(group_id.clone()));
+            let incident = Call::get_open_calls_from(
+                call_table.clone(),
+                Region::UsEast1,
+                group_id.clone(),
                 phone_number.clone(),
-                "group_id".to_string(),
             )
-            .unwrap()
-            .get_providers(Utc::now().into())
-            .unwrap_or_else(Vec::new);
+            .await
+            .map_err(|_e| HandlerError::from("CallReadFail"))?
+            .into_iter()
+            .filter(|call| call.absorbs_repeat(received_at, policy.dedup_window()))
+            .max_by_key(|call| call.last_heard());
+            if let Some(incident) = incident {
+                return attach_follow_up(
+                    incident,
+                    call_id,
+                    message_url,
+                    caller,
+                    call_table,
+                    timeline_table,
+                    queue_url,
+                )
+                .await;
+            }
 
-            let call: Call = Call {
-                call_id: Uuid::new_v4(),
-                group_id: phone_number.clone(),
-                message_url: request_body["RecordingUrl"].as_str().unwrap().to_string(),
-                phone_number,
-                users,
-                handled: false,
+            let menu_option = match query.get("option") {
+                Some(digit) => {
+                    IvrMenu::get_ivr_menu(env::var("IVR_TABLE")?, Region::UsEast1, group_id.clone())
+                        .await
+                        .and_then(|menu| menu.option(digit).cloned())
+                }
+                None => None,
             };
+            let mut call: Call = Call::new_call(
+                call_id,
+                group_id,
+                group_number,
+                message_url,
+                phone_number,
+                received_at,
+            );
+            call.caller = caller;
+            call.menu_option = menu_option;
             let dynamo_client = DynamoDbClient::new(Region::UsEast1);
-            let call_future = call.async_write_call(&dynamo_client, call_table);
-            
+            // The write bumps the stored copy's version, which the queued message doesn't carry.
+            // It has to land before the message is queued, or receive_message may not find it.
+            let mut stored_call = call.clone();
+            stored_call
+                .async_write_call(&dynamo_client, call_table)
+                .await
+                .map_err(|_e| HandlerError::from("CallWriteFail"))?;
+
             let sqs_client = SqsClient::new(Region::UsEast1);
-            let sqs_future = call.sqs_push(&sqs_client, 20);
-            
-            try_join!(
-                call_future.map_err(|e| { HandlerError::from("CallWriteFail") }),
-                sqs_future.map_err(|e| {
+            // receive_message pages the first escalation level straight away
+            call.sqs_push(&sqs_client, queue_url.clone(), 0)
+                .await
+                .map_err(|e| {
                     let string;
                     HandlerError::from(match e {
                         Service(InvalidMessageContents(s)) => {
@@ -88,10 +314,34 @@ async fn handler(request: Request, _context: Context) -> Result<Response<Body>,
                         }
                         _ => "OtherErrorFound",
                     })
-                })
-            )?;
+                })?;
+            // Pages link Twilio's copy of the recording until this has run
+            EscalationMessage::new_archive(&call, received_at)
+                .send(&sqs_client, queue_url)
+                .await
+                .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+            TimelineEvent::append(
+                timeline_table,
+                Region::UsEast1,
+                call.call_id,
+                EventKind::Created,
+                match &call.menu_option {
+                    Some(option) => format!(
+                        "Voicemail from {} to {}, chose {} ({:?} severity)",
+                        call.caller.describe(&call.phone_number),
+                        call.group_id,
+                        option.label,
+                        option.severity
+                    ),
+                    None => format!(
+                        "Voicemail from {} to {}",
+                        call.caller.describe(&call.phone_number),
+                        call.group_id
+                    ),
+                },
+            )
+            .await;
             Ok("Success!".into_response())
         }
-        _ => Ok("Invalid Request".into_response()),
     }
 }
@@ -1,32 +1,456 @@
-use aws_lambda_events::event::sqs::SqsEvent;
+use aws_lambda_events::event::sqs::{SqsEvent, SqsMessage};
+use chrono::Utc;
+use dynomite::dynamodb::{DynamoDbClient, PutItemError::ConditionalCheckFailed};
 use lambda_runtime::{error::HandlerError, lambda, Context};
-use log::Level::Info;
-use models::call::Call;
+use log::{info, warn, Level::Info};
+use models::{
+    archive::{archive_recording, recording_key, s3_region, signed_url, SIGNED_URL_EXPIRY},
+    call::{Call, ReadError},
+    channel::{
+        EmailChannel, NotificationChannel, Page, SmsChannel, SmtpConfig, VoiceChannel,
+        WebhookChannel,
+    },
+    escalation::{EscalationPolicy, TerminalAction},
+    notify::{notify_users, page_url, TwilioNotifier},
+    queue::EscalationMessage,
+    schedule::Schedule,
+    timeline::{EventKind, TimelineEvent},
+};
 use rusoto_core::{Region, RusotoError::Service};
+use rusoto_s3::S3Client;
 use rusoto_sqs::{
+    MessageAttributeValue,
     SendMessageError::{InvalidMessageContents, UnsupportedOperation},
-    SqsClient,
+    SendMessageRequest, Sqs, SqsClient,
 };
+use serde::Serialize;
 use simple_logger::init_with_level;
-use std::env;
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+};
 fn main() {
     init_with_level(Info).unwrap();
     lambda!(handler);
 }
 
+/// Response Lambda reads to retry only the listed messages of a batch
+#[derive(Serialize)]
+struct SqsBatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    batch_item_failures: Vec<BatchItemFailure>,
+}
+
+#[derive(Serialize)]
+struct BatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    item_identifier: String,
+}
+
+/// Why a record was not processed
+enum RecordError {
+    /// The message can never succeed, so it is parked on the dead-letter queue
+    Poison(String),
+    /// Worth retrying on the next delivery
+    Retry(HandlerError),
+}
+
+impl From<HandlerError> for RecordError {
+    fn from(e: HandlerError) -> Self {
+        RecordError::Retry(e)
+    }
+}
+
+impl From<VarError> for RecordError {
+    fn from(e: VarError) -> Self {
+        RecordError::Retry(e.into())
+    }
+}
+
+/// Copies a poison message to the dead-letter queue along with why it failed
+async fn dead_letter(record: &SqsMessage, reason: &str) -> Result<(), HandlerError> {
+    let mut attributes = HashMap::new();
+    for (name, value) in &[
+        ("reason", reason),
+        (
+            "source_message_id",
+            record.message_id.as_deref().unwrap_or(""),
+        ),
+    ] {
+        attributes.insert(
+            name.to_string(),
+            MessageAttributeValue {
+                data_type: "String".to_string(),
+                string_value: Some(value.to_string()),
+                ..MessageAttributeValue::default()
+            },
+        );
+    }
+    SqsClient::new(Region::UsEast1)
+        .send_message(SendMessageRequest {
+            queue_url: env::var("DEAD_LETTER_QUEUE_URL")?,
+            message_body: record.body.clone().unwrap_or_default(),
+            message_attributes: Some(attributes),
+            ..SendMessageRequest::default()
+        })
+        .await
+        .map_err(|_e| HandlerError::from("DeadLetterFail"))?;
+    Ok(())
+}
+
+/// An optional variable, which deployments leave empty when unset
+fn setting(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Mail server for email pages, configured through `SMTP_*` variables.
+/// Without `SMTP_HOST` nobody is paged by email.
+fn smtp_config() -> Option<SmtpConfig> {
+    let host = setting("SMTP_HOST")?;
+    Some(SmtpConfig {
+        host,
+        port: setting("SMTP_PORT")
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(587),
+        // Only a local stand-in such as MailHog should set SMTP_STARTTLS=false
+        starttls: setting("SMTP_STARTTLS").as_deref() != Some("false"),
+        credentials: match (setting("SMTP_USERNAME"), setting("SMTP_PASSWORD")) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        },
+        from: setting("SMTP_FROM").unwrap_or_else(|| "pager@localhost".to_string()),
+    })
+}
+
+/// Pages `call.users` for `level` by whichever of their notification rules
+/// are due `after` seconds into the step, recording each delivery on the
+/// timeline. Returns whether anyone was reached.
+async fn page_users(
+    call: &Call,
+    level: u32,
+    after: i64,
+    timeline_table: &str,
+) -> Result<bool, HandlerError> {
+    let notifier = TwilioNotifier::new(env::var("TWILIO_SID")?, env::var("TWILIO_TOKEN")?)
+        .map_err(|_e| HandlerError::from("TwilioClientFail"))?;
+    let page_url = page_url(&env::var("PAGE_CALL_URL")?, call, level);
+    let recording_url = match &call.recording_key {
+        Some(key) => signed_url(
+            env::var("RECORDING_BUCKET")?,
+            &s3_region(env::var("S3_ENDPOINT").ok()),
+            key.clone(),
+            SIGNED_URL_EXPIRY,
+        )
+        .await
+        .unwrap_or_else(|| call.message_url.clone()),
+        None => call.message_url.clone(),
+    };
+    if call.users.is_empty() && after == 0 {
+        TimelineEvent::append(
+            timeline_table.to_string(),
+            Region::UsEast1,
+            call.call_id,
+            EventKind::DeliveryFailed,
+            format!("Nobody to page at level {}", level),
+        )
+        .await;
+    }
+    let (sms, voice) = (SmsChannel::new(&notifier), VoiceChannel::new(&notifier));
+    let webhook = WebhookChannel::new();
+    let email = smtp_config().map(EmailChannel::new);
+    let mut channels: Vec<&(dyn NotificationChannel + Sync)> = vec![&sms, &voice, &webhook];
+    if let Some(email) = &email {
+        channels.push(email);
+    }
+    let page = Page::new_page(call, &page_url, &recording_url);
+    let deliveries = notify_users(&channels, call, &page, after).await;
+    for delivery in &deliveries {
+        let (kind, detail) = match &delivery.result {
+            Ok(()) => (
+                EventKind::Notified,
+                format!("Notified {} via {:?}", delivery.address, delivery.kind),
+            ),
+            Err(e) => {
+                warn!(
+                    "{:?} to {} failed: {:?}",
+                    delivery.kind, delivery.address, e
+                );
+                (
+                    EventKind::DeliveryFailed,
+                    format!(
+                        "{:?} to {} failed: {:?}",
+                        delivery.kind, delivery.address, e
+                    ),
+                )
+            }
+        };
+        TimelineEvent::append(
+            timeline_table.to_string(),
+            Region::UsEast1,
+            call.call_id,
+            kind,
+            detail,
+        )
+        .await;
+    }
+    Ok(deliveries.iter().any(|delivery| delivery.result.is_ok()))
+}
+
+/// Writes the call as read, so a duplicate delivery racing this one loses
+/// and pages nobody. If anyone else wrote the call in between the message is
+/// retried: the redelivery is dropped if the attempt was claimed, and
+/// otherwise pages with whatever changed, e.g. a newly stored transcription.
+async fn claim(call: &mut Call, call_table: String) -> Result<(), RecordError> {
+    let dynamo_client = DynamoDbClient::new(Region::UsEast1);
+    match call.async_write_call(&dynamo_client, call_table).await {
+        Ok(_) => Ok(()),
+        Err(Service(ConditionalCheckFailed(_))) => Err(HandlerError::from("CallChanged").into()),
+        Err(_e) => Err(HandlerError::from("CallWriteFail").into()),
+    }
+}
+
+/// Runs the policy's terminal action for a call that has used up its
+/// attempts, or whose policy has no levels left to page
+async fn give_up(
+    mut call: Call,
+    policy: &EscalationPolicy,
+    call_table: String,
+    group_table: String,
+    timeline_table: String,
+) -> Result<String, RecordError> {
+    let attempts = call.attempts;
+    let terminal = policy.terminal().clone();
+    match &terminal {
+        TerminalAction::PageGroup => {
+            call.users = Schedule::get_schedule(
+                group_table,
+                Region::UsEast1,
+                policy.group_id().to_string(),
+                "group_id".to_string(),
+            )
+            .await
+            .map(|schedule| schedule.members())
+            .unwrap_or_else(Vec::new);
+        }
+        TerminalAction::NotifyManagers(managers) => call.users = managers.clone(),
+        TerminalAction::Expire => {}
+    }
+    let transition = match terminal {
+        TerminalAction::Expire => call.expire(Utc::now()),
+        _ => {
+            call.attempts += 1;
+            call.notify(Utc::now())
+        }
+    };
+    transition.map_err(|_e| HandlerError::from("IllegalTransition"))?;
+    call.terminal_action = Some(terminal.clone());
+    claim(&mut call, call_table).await?;
+    if terminal != TerminalAction::Expire {
+        page_users(&call, call.level, 0, &timeline_table).await?;
+    }
+    TimelineEvent::append(
+        timeline_table,
+        Region::UsEast1,
+        call.call_id,
+        match terminal {
+            TerminalAction::Expire => EventKind::Expired,
+            _ => EventKind::Escalated,
+        },
+        format!("Gave up after {} attempts: {:?}", attempts, terminal),
+    )
+    .await;
+    Ok("Escalation exhausted!".to_string())
+}
+
+/// Archives the call's recordings that aren't archived yet and stores
+/// where they went. Any that fail are tried again with the message.
+async fn archive_call(
+    call: &Call,
+    call_table: String,
+    timeline_table: String,
+) -> Result<String, RecordError> {
+    let bucket = env::var("RECORDING_BUCKET")?;
+    let s3_client = S3Client::new(s3_region(env::var("S3_ENDPOINT").ok()));
+    let (sid, token) = (env::var("TWILIO_SID")?, env::var("TWILIO_TOKEN")?);
+    let mut archived = Vec::new();
+    let mut failed = 0;
+    for (number, message_url) in call.unarchived_recordings() {
+        let key = recording_key(call, number);
+        match archive_recording(&s3_client, &message_url, key, bucket.clone(), &sid, &token).await {
+            Ok(key) => archived.push((number, key)),
+            Err(e) => {
+                warn!(
+                    "Archiving recording {} of {} failed: {:?}",
+                    number, call.call_id, e
+                );
+                failed += 1;
+            }
+        }
+    }
+    if !archived.is_empty() {
+        Call::update_call(
+            call_table,
+            Region::UsEast1,
+            call.call_id.to_string(),
+            |call| -> Result<(), ()> {
+                for (number, key) in &archived {
+                    call.set_recording_key(*number, key.clone());
+                }
+                Ok(())
+            },
+        )
+        .await
+        .map_err(|_e| HandlerError::from("CallWriteFail"))?;
+    }
+    for (_, key) in &archived {
+        TimelineEvent::append(
+            timeline_table.clone(),
+            Region::UsEast1,
+            call.call_id,
+            EventKind::Archived,
+            format!("Recording archived as {}", key),
+        )
+        .await;
+    }
+    match failed {
+        0 => Ok(format!("Archived {} recordings", archived.len())),
+        _ => Err(HandlerError::from("ArchiveFail").into()),
+    }
+}
+
 #[tokio::main]
-async fn handler(sqs_event: SqsEvent, _context: Context) -> Result<String, HandlerError> {
-    let message: String = sqs_event.records[0].body.as_ref().unwrap().to_string();
+async fn handler(sqs_event: SqsEvent, _context: Context) -> Result<SqsBatchResponse, HandlerError> {
+    let mut batch_item_failures = Vec::new();
+    for record in sqs_event.records {
+        let message_id = record.message_id.clone().unwrap_or_default();
+        let result = match record.body.as_deref().map(EscalationMessage::parse) {
+            Some(Ok(message)) => process_call(message).await,
+            Some(Err(e)) => Err(RecordError::Poison(format!("{:?}", e))),
+            None => Err(RecordError::Poison("Missing body".to_string())),
+        };
+        let retry = match result {
+            Ok(outcome) => {
+                info!("{}: {}", message_id, outcome);
+                false
+            }
+            Err(RecordError::Poison(reason)) => {
+                warn!("{} is poison: {}", message_id, reason);
+                match dead_letter(&record, &reason).await {
+                    Ok(()) => false,
+                    Err(e) => {
+                        warn!("{} could not be dead-lettered: {:?}", message_id, e);
+                        true
+                    }
+                }
+            }
+            Err(RecordError::Retry(e)) => {
+                warn!("{} failed, will retry: {:?}", message_id, e);
+                true
+            }
+        };
+        if retry {
+            batch_item_failures.push(BatchItemFailure {
+                item_identifier: message_id,
+            });
+        }
+    }
+    Ok(SqsBatchResponse {
+        batch_item_failures,
+    })
+}
+
+/// Pages the next escalation step for the call in `message`, or archives
+/// its recordings. Messages delivered before they are due are queued again
+/// rather than processed.
+async fn process_call(message: EscalationMessage) -> Result<String, RecordError> {
     let call_table: String = env::var("CALL_TABLE")?;
-    let call = Call::get_call(call_table, Region::UsEast1, message)
-        .ok_or_else(|| HandlerError::from("Call Not Found"))?;
-    if call.handled {
-        Ok("Call Handled!".to_string())
-    } else {
+    let group_table: String = env::var("GROUP_TABLE")?;
+    let escalation_table: String = env::var("ESCALATION_TABLE")?;
+    let timeline_table: String = env::var("TIMELINE_TABLE")?;
+    let queue_url: String = env::var("ESCALATION_QUEUE_URL")?;
+    let mut call = Call::get_call(
+        call_table.clone(),
+        Region::UsEast1,
+        message.call_id.to_string(),
+    )
+    .await
+    .map_err(|e| match e {
+        // The call may not be readable yet, or DynamoDB may be throttling
+        ReadError::NotFound => HandlerError::from("Call Not Found").into(),
+        ReadError::Read(_) => HandlerError::from("CallReadFail").into(),
+        ReadError::Malformed(e) => RecordError::Poison(format!("Unreadable call: {:?}", e)),
+    })?;
+    if message.archive {
+        return archive_call(&call, call_table, timeline_table).await;
+    }
+    if !call.state.is_open() || call.terminal_action.is_some() {
+        return Ok("Call Handled!".to_string());
+    }
+    if message.attempt != call.attempts {
+        return Ok(match message.notify_after {
+            Some(_) => "Step already over!".to_string(),
+            None => "Duplicate delivery!".to_string(),
+        });
+    }
+    if message.due > Utc::now() {
+        // SQS can only delay a message 15 minutes, so longer waits are chained
         let sqs_client = SqsClient::new(Region::UsEast1);
-        let delay_time = 50; // Get from escalation rules
-        // TODO: Notify user
-        call.sqs_push(&sqs_client, delay_time).await.map_err(|e| {
+        message
+            .send(&sqs_client, queue_url)
+            .await
+            .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+        return Ok(format!("Deferred until {}", message.due));
+    }
+    if let Some(after) = message.notify_after {
+        if after <= call.reminded {
+            return Ok("Duplicate delivery!".to_string());
+        }
+        call.reminded = after;
+        claim(&mut call, call_table).await?;
+        page_users(&call, message.level, after, &timeline_table).await?;
+        return Ok(format!("Sent rules due {}s into the step", after));
+    }
+    let policy = EscalationPolicy::get_escalation_policy(
+        escalation_table.clone(),
+        Region::UsEast1,
+        call.route().to_string(),
+    )
+    .await
+    .unwrap_or_else(|| EscalationPolicy::default_policy(call.route().to_string()));
+
+    // A level removed while the call was open falls back to the last one left
+    let level = match policy.level(call.level).or_else(|| policy.last_level()) {
+        Some(level) if !policy.exhausted(call.attempts) => level,
+        _ => return give_up(call, &policy, call_table, group_table, timeline_table).await,
+    };
+    call.users = level
+        .resolve_users(
+            group_table,
+            escalation_table,
+            Region::UsEast1,
+            Utc::now().into(),
+        )
+        .await;
+    let paged_level = call.level;
+    // Once the levels run out, keep paging the last one until the attempts do
+    let (next_level, next_repeat) = policy
+        .next_step(call.level, call.repeat)
+        .unwrap_or((call.level, call.repeat));
+    call.level = next_level;
+    call.repeat = next_repeat;
+    call.attempts += 1;
+    call.reminded = 0;
+    let started = Utc::now();
+    call.notify(started)
+        .map_err(|_e| HandlerError::from("IllegalTransition"))?;
+
+    // Queue the next step before claiming this one: if the claim then fails
+    // the extra message is dropped as a duplicate, whereas claiming first
+    // and failing to queue would stop escalation altogether
+    let sqs_client = SqsClient::new(Region::UsEast1);
+    call.sqs_push(&sqs_client, queue_url.clone(), level.timeout())
+        .await
+        .map_err(|e| {
             let string;
             HandlerError::from(match e {
                 Service(InvalidMessageContents(s)) => {
@@ -40,6 +464,56 @@ async fn handler(sqs_event: SqsEvent, _context: Context) -> Result<String, Handl
                 _ => "OtherErrorFound",
             })
         })?;
-        Ok("Renotified user!".to_string())
+    claim(&mut call, call_table).await?;
+    let reached = page_users(&call, paged_level, 0, &timeline_table).await?;
+    // Users' later rules only matter while this step is still running
+    let mut reminders: Vec<i64> = call
+        .users
+        .iter()
+        .flat_map(|user| user.later_rules())
+        .filter(|after| *after < level.timeout())
+        .collect();
+    reminders.sort_unstable();
+    reminders.dedup();
+    for after in &reminders {
+        EscalationMessage::new_reminder(&call, paged_level, started, *after)
+            .send(&sqs_client, queue_url.clone())
+            .await
+            .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+    }
+    if !reached && reminders.is_empty() {
+        // Nobody can be paged at this step, so waiting out its timeout only
+        // delays the next one. This carries the same attempt as the message
+        // queued above, so whichever is delivered second is dropped.
+        call.sqs_push(&sqs_client, queue_url, 0)
+            .await
+            .map_err(|_e| HandlerError::from("SqsPushFail"))?;
+        TimelineEvent::append(
+            timeline_table.clone(),
+            Region::UsEast1,
+            call.call_id,
+            EventKind::Requeued,
+            "Nobody was reached, moving on to the next step now".to_string(),
+        )
+        .await;
+    }
+    if next_level != paged_level {
+        TimelineEvent::append(
+            timeline_table.clone(),
+            Region::UsEast1,
+            call.call_id,
+            EventKind::Escalated,
+            format!("Escalating to level {}", next_level),
+        )
+        .await;
     }
+    TimelineEvent::append(
+        timeline_table,
+        Region::UsEast1,
+        call.call_id,
+        EventKind::Requeued,
+        format!("Re-queued with delay {}s", level.timeout()),
+    )
+    .await;
+    Ok("Renotified user!".to_string())
 }
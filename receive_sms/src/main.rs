@@ -0,0 +1,167 @@
+use chrono::Utc;
+use dynomite::dynamodb::DynamoDbClient;
+use lambda_http::{
+    http::header::{HeaderValue, CONTENT_TYPE},
+    lambda, Body,
+    Body::Text,
+    IntoResponse, Request, RequestExt, Response,
+};
+use lambda_runtime::{error::HandlerError, Context};
+use log::Level::Info;
+use models::{
+    call::{Call, IllegalTransition, UpdateError},
+    group::{group_key, Group},
+    schedule::Schedule,
+    sms::{reply_twiml, who_reply, SmsCommand, WhoQuery},
+    timeline::{EventKind, TimelineEvent},
+    webhook::{reject, required, verify_webhook, SIGNATURE_HEADER},
+};
+use rusoto_core::Region;
+use simple_logger::init_with_level;
+use std::env;
+
+fn main() {
+    init_with_level(Info).unwrap();
+    lambda!(handler);
+}
+
+/// TwiML response texting `reply` back to the sender
+fn reply_response(reply: &str) -> Response<Body> {
+    let mut twiml = reply_twiml(reply).into_response();
+    twiml.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str("application/xml").unwrap(),
+    );
+    twiml
+}
+
+/// Answers a "WHO" query, but only to members of the group texted
+async fn answer_who(
+    query: WhoQuery,
+    from: &str,
+    group_number: &str,
+) -> Result<String, HandlerError> {
+    let group = Group::get_group_by_number(
+        env::var("GROUPS_TABLE")?,
+        env::var("NUMBER_TABLE")?,
+        &DynamoDbClient::new(Region::UsEast1),
+        group_number.to_string(),
+    )
+    .await;
+    let schedule = Schedule::get_schedule(
+        env::var("GROUP_TABLE")?,
+        Region::UsEast1,
+        group_key(group.as_ref(), group_number),
+        "group_id".to_string(),
+    )
+    .await;
+    Ok(match schedule {
+        Some(schedule)
+            if group
+                .as_ref()
+                .and_then(|group| group.member(from))
+                .is_some()
+                || schedule.members().iter().any(|user| user.has_phone(from)) =>
+        {
+            let offset = group.map_or_else(|| schedule.offset(), |group| group.offset());
+            who_reply(&schedule, query.at(Utc::now().with_timezone(&offset)))
+        }
+        Some(_) => "Only members of this group can ask who is on call.".to_string(),
+        None => "No on-call schedule is set up for this number.".to_string(),
+    })
+}
+
+/// Twilio webhook for SMS sent to a group number
+#[tokio::main]
+async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
+    let request_body = match verify_webhook(
+        &env::var("TWILIO_TOKEN")?,
+        &env::var("RECEIVE_SMS_URL")?,
+        request.query_string_parameters().iter(),
+        match request.body() {
+            Text(string) => string.as_ref(),
+            _ => "",
+        },
+        request
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok()),
+    ) {
+        Ok(request_body) => request_body,
+        Err(e) => return Ok(reject(e)),
+    };
+    let (from, group_number) = match (
+        required(&request_body, "From"),
+        required(&request_body, "To"),
+    ) {
+        (Ok(from), Ok(group_number)) => (from, group_number),
+        (Err(e), _) | (_, Err(e)) => return Ok(reject(e)),
+    };
+    let text = request_body["Body"].as_str().unwrap_or_default();
+
+    if let Some(query) = WhoQuery::parse(text) {
+        return Ok(reply_response(
+            &answer_who(query, from, group_number).await?,
+        ));
+    }
+
+    let reply = match SmsCommand::parse(text) {
+        Some(command) => {
+            let call_table = env::var("CALL_TABLE")?;
+            let short_code = command.short_code().to_string();
+            let call =
+                Call::get_open_calls(call_table.clone(), Region::UsEast1, short_code.clone())
+                    .await
+                    .map_err(|_e| HandlerError::from("CallReadFail"))?
+                    .into_iter()
+                    .find(|call| {
+                        call.group_number == group_number
+                            && call.users.iter().any(|user| user.has_phone(from))
+                    });
+            match call {
+                Some(call) => {
+                    let update = Call::update_call(
+                        call_table,
+                        Region::UsEast1,
+                        call.call_id.to_string(),
+                        |call| match command {
+                            SmsCommand::Ack(_) => call.acknowledge(from.to_string(), Utc::now()),
+                            SmsCommand::Resolve(_) => call.resolve(from.to_string(), Utc::now()),
+                        },
+                    )
+                    .await;
+                    match update {
+                        Ok((call, ())) => {
+                            TimelineEvent::append(
+                                env::var("TIMELINE_TABLE")?,
+                                Region::UsEast1,
+                                call.call_id,
+                                match command {
+                                    SmsCommand::Ack(_) => EventKind::Acknowledged,
+                                    SmsCommand::Resolve(_) => EventKind::Resolved,
+                                },
+                                format!("{:?} by {} via SMS", call.state, from),
+                            )
+                            .await;
+                            match command {
+                                SmsCommand::Ack(_) => {
+                                    format!("Acknowledged page {}.", short_code)
+                                }
+                                SmsCommand::Resolve(_) => format!("Resolved page {}.", short_code),
+                            }
+                        }
+                        Err(UpdateError::Rejected(IllegalTransition { from, .. })) => {
+                            format!("Page {} is already {:?}.", short_code, from)
+                        }
+                        Err(_) => return Err(HandlerError::from("CallWriteFail")),
+                    }
+                }
+                None => format!("No open page {} was sent to this number.", short_code),
+            }
+        }
+        None => {
+            "Reply ACK <code> to acknowledge a page, RESOLVE <code> to resolve it or WHO [day] [time] to see who is on call.".to_string()
+        }
+    };
+    Ok(reply_response(&reply))
+}
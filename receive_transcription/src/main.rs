@@ -0,0 +1,213 @@
+use dynomite::dynamodb::DynamoDbClient;
+use lambda_http::{
+    http::StatusCode, lambda, Body, Body::Text, IntoResponse, Request, RequestExt, Response,
+};
+use lambda_runtime::{error::HandlerError, Context};
+use log::{warn, Level::Info};
+use models::{
+    call::{Call, UpdateError},
+    group::{group_key, Group},
+    notify::{repeat_transcription_sms_body, transcription_sms_body, Notifier, TwilioNotifier},
+    timeline::{EventKind, TimelineEvent},
+    webhook::{reject, required, verify_webhook, WebhookError, SIGNATURE_HEADER},
+};
+use rusoto_core::Region;
+use serde_json::Value;
+use simple_logger::init_with_level;
+use std::env;
+use uuid::Uuid;
+
+fn main() {
+    init_with_level(Info).unwrap();
+    lambda!(handler);
+}
+
+/// Twilio retries on a 5xx, by which time the call may have been stored
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body("Call Not Found".into())
+        .unwrap()
+}
+
+/// Stores the transcription of a repeat call, which joined an earlier
+/// call's incident as a follow-up instead of becoming a call of its own
+async fn transcribe_follow_up(
+    request_body: &Value,
+    call_id: Uuid,
+    text: String,
+    call_table: String,
+) -> Result<Response<Body>, HandlerError> {
+    let (group_number, phone_number) =
+        match (required(request_body, "To"), required(request_body, "From")) {
+            (Ok(group_number), Ok(phone_number)) => {
+                (group_number.to_string(), phone_number.to_string())
+            }
+            (Err(e), _) | (_, Err(e)) => return Ok(reject(e)),
+        };
+    let group = Group::get_group_by_number(
+        env::var("GROUPS_TABLE")?,
+        env::var("NUMBER_TABLE")?,
+        &DynamoDbClient::new(Region::UsEast1),
+        group_number.clone(),
+    )
+    .await;
+    // The incident may have been resolved since the caller rang back
+    let incident = Call::get_calls_from(
+        call_table.clone(),
+        Region::UsEast1,
+        group_key(group.as_ref(), &group_number),
+        phone_number,
+    )
+    .await
+    .map_err(|_e| HandlerError::from("CallReadFail"))?
+    .into_iter()
+    .find(|call| {
+        call.follow_ups
+            .iter()
+            .any(|follow_up| follow_up.call_id == Some(call_id))
+    });
+    let incident = match incident {
+        Some(incident) => incident,
+        None => return Ok(not_found()),
+    };
+    let update = Call::update_call(
+        call_table,
+        Region::UsEast1,
+        incident.call_id.to_string(),
+        |call| -> Result<(), ()> {
+            if call.transcribe_follow_up(call_id, text.clone()) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        },
+    )
+    .await;
+    let incident = match update {
+        Ok((incident, ())) => incident,
+        Err(UpdateError::NotFound) | Err(UpdateError::Rejected(())) => return Ok(not_found()),
+        Err(_) => return Err(HandlerError::from("CallWriteFail")),
+    };
+    let timeline_table = env::var("TIMELINE_TABLE")?;
+    TimelineEvent::append(
+        timeline_table.clone(),
+        Region::UsEast1,
+        incident.call_id,
+        EventKind::Transcribed,
+        format!("Transcribed repeat call: \"{}\"", text),
+    )
+    .await;
+    let body = repeat_transcription_sms_body(&incident, &text);
+    send_transcript(&incident, &body, timeline_table).await
+}
+
+/// Texts `body` to everyone already paged for `call`. Pages sent from now
+/// on include the text; only earlier ones need a follow-up.
+async fn send_transcript(
+    call: &Call,
+    body: &str,
+    timeline_table: String,
+) -> Result<Response<Body>, HandlerError> {
+    if call.attempts == 0 || !call.state.is_open() {
+        return Ok("Success!".into_response());
+    }
+    let notifier = TwilioNotifier::new(env::var("TWILIO_SID")?, env::var("TWILIO_TOKEN")?)
+        .map_err(|_e| HandlerError::from("TwilioClientFail"))?;
+    for user in &call.users {
+        let (kind, detail) = match notifier
+            .send_sms(&call.group_number, &user.number, body)
+            .await
+        {
+            Ok(()) => (
+                EventKind::Notified,
+                format!("Sent transcript to {} via SMS", user.number),
+            ),
+            Err(e) => {
+                warn!("Transcript SMS to {} failed: {:?}", user.number, e);
+                (
+                    EventKind::DeliveryFailed,
+                    format!("Transcript SMS to {} failed: {:?}", user.number, e),
+                )
+            }
+        };
+        TimelineEvent::append(
+            timeline_table.clone(),
+            Region::UsEast1,
+            call.call_id,
+            kind,
+            detail,
+        )
+        .await;
+    }
+    Ok("Success!".into_response())
+}
+
+/// Twilio's transcribeCallback for a voicemail. Stores the text on the call
+/// and texts it to anyone who was already paged without it, or on the
+/// follow-up if it was a repeat call.
+#[tokio::main]
+async fn handler(request: Request, _context: Context) -> Result<Response<Body>, HandlerError> {
+    let query = request.query_string_parameters();
+    let request_body = match verify_webhook(
+        &env::var("TWILIO_TOKEN")?,
+        &env::var("TRANSCRIPTION_URL")?,
+        query.iter(),
+        match request.body() {
+            Text(string) => string.as_ref(),
+            _ => "",
+        },
+        request
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok()),
+    ) {
+        Ok(request_body) => request_body,
+        Err(e) => return Ok(reject(e)),
+    };
+    let call_id = match query
+        .get("call_id")
+        .and_then(|call_id| Uuid::parse_str(call_id).ok())
+    {
+        Some(call_id) => call_id,
+        None => return Ok(reject(WebhookError::MissingField("call_id".to_string()))),
+    };
+    if request_body["TranscriptionStatus"].as_str() != Some("completed") {
+        return Ok("Transcription failed".into_response());
+    }
+    let text = request_body["TranscriptionText"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+
+    let call_table = env::var("CALL_TABLE")?;
+    let update = Call::update_call(
+        call_table.clone(),
+        Region::UsEast1,
+        call_id.to_string(),
+        |call| -> Result<(), ()> {
+            call.transcription = Some(text.clone());
+            Ok(())
+        },
+    )
+    .await;
+    let call = match update {
+        Ok((call, ())) => call,
+        Err(UpdateError::NotFound) => {
+            return transcribe_follow_up(&request_body, call_id, text, call_table).await
+        }
+        Err(_) => return Err(HandlerError::from("CallWriteFail")),
+    };
+    let timeline_table = env::var("TIMELINE_TABLE")?;
+    TimelineEvent::append(
+        timeline_table.clone(),
+        Region::UsEast1,
+        call.call_id,
+        EventKind::Transcribed,
+        format!("Transcribed: \"{}\"", text),
+    )
+    .await;
+
+    let body = transcription_sms_body(&call).unwrap_or_default();
+    send_transcript(&call, &body, timeline_table).await
+}